@@ -0,0 +1,386 @@
+use log::info;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::kube::{self, Admin, EndpointStatus, Override, Services};
+use crate::metrics::{self, Operation};
+use crate::status;
+
+// how long a force restored endpoint is kept in k8s if no ttl is given
+pub(crate) const DEFAULT_RESTORE_TTL: u64 = 600;
+
+// what an operator does to an endpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Action {
+    // remove it and keep it out, until the ttl if any
+    Drain,
+    // restore it and keep it in until the ttl
+    Restore,
+    // hand it back to probes
+    Release,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drain" => Ok(Self::Drain),
+            "restore" => Ok(Self::Restore),
+            "release" => Ok(Self::Release),
+            _ => Err(Error::config(format!("unknown action {}", s))),
+        }
+    }
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Drain => "drain",
+            Self::Restore => "restore",
+            Self::Release => "release",
+        }
+    }
+}
+
+// an endpoint as given by an operator, IP:PORT for one port or IP for all
+//  ports of the address
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Addr(SocketAddr),
+    Ip(IpAddr),
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(Self::Addr(addr));
+        }
+        IpAddr::from_str(s)
+            .map(Self::Ip)
+            .map_err(|_| Error::config(format!("invalid endpoint {}", s)))
+    }
+}
+
+impl Target {
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Addr(target) => target == addr,
+            Self::Ip(ip) => *ip == addr.ip(),
+        }
+    }
+}
+
+// Applies action to the endpoints of namespace/name matching endpoint, they
+//  are removed from or restored to k8s the same way probes do it.
+// Returns the state of the service afterwards.
+pub(crate) async fn apply(
+    svcs: &Services,
+    namespace: &str,
+    name: &str,
+    endpoint: &str,
+    action: Action,
+    ttl: Option<Duration>,
+) -> Result<status::Service> {
+    let target = Target::from_str(endpoint)?;
+    let svc = svcs
+        .read()
+        .await
+        .get(&kube::key(namespace, name))
+        .cloned()
+        .ok_or_else(|| Error::not_found(format!("no service {}/{}", namespace, name)))?;
+    let mut svc = svc.write().await;
+    let matched: Vec<SocketAddr> = svc
+        .endpoints
+        .iter()
+        .map(|ep| ep.addr)
+        .filter(|addr| target.matches(addr))
+        .collect();
+    if matched.is_empty() {
+        return Err(Error::not_found(format!(
+            "no endpoint {} in {}/{}",
+            endpoint, namespace, name
+        )));
+    }
+
+    let until = match action {
+        Action::Restore => Some(ttl.unwrap_or(Duration::from_secs(DEFAULT_RESTORE_TTL))),
+        _ => ttl,
+    }
+    .map(|ttl| Instant::now() + ttl);
+    info!(
+        "{} {} of {}/{} by operator",
+        action.as_str(),
+        endpoint,
+        namespace,
+        name
+    );
+    // the override is only set once k8s is as it says, one left behind by a
+    //  failed write would keep probes from ever putting it right
+    for addr in matched {
+        // one whose pod is gone is forgotten when restoring another
+        let i = match svc.endpoints.iter().position(|ep| ep.addr == addr) {
+            Some(i) => i,
+            None => continue,
+        };
+        let status = svc.endpoints[i].status.clone();
+        let state = match action {
+            Action::Release => {
+                svc.endpoints[i].admin = None;
+                continue;
+            }
+            Action::Drain => {
+                // an earlier endpoint of the same IP may have taken it out
+                if status != EndpointStatus::Removed {
+                    svc.remove_ep(i).await?;
+                    metrics::operation(&svc.namespace, &svc.name, Operation::Remove);
+                }
+                Override::Drained
+            }
+            Action::Restore => {
+                if status != EndpointStatus::Healthy {
+                    svc.restore_ep(i).await?;
+                    if svc.endpoints.iter().any(|ep| ep.addr == addr) {
+                        metrics::operation(&svc.namespace, &svc.name, Operation::Restore);
+                    }
+                }
+                Override::Restored
+            }
+        };
+        // forgotten, rather than restored, if its pod is gone, which moves
+        //  the ones after it
+        if let Some(ep) = svc.endpoints.iter_mut().find(|ep| ep.addr == addr) {
+            ep.admin = Some(Admin { state, until });
+        }
+    }
+    Ok(status::Service::from(&*svc))
+}
+
+// a request to the admin API of a running ephc, from the command line
+#[derive(Debug, Clone)]
+pub(crate) struct Command {
+    pub action: Action,
+    // base URL of the running ephc
+    pub server: String,
+    pub namespace: String,
+    pub name: String,
+    pub endpoint: String,
+    // seconds
+    pub ttl: Option<u64>,
+}
+
+impl Command {
+    // sends the request and prints the state of the service it responds with
+    pub async fn run(&self) -> Result<()> {
+        let mut url = url::Url::parse(&self.server)
+            .map_err(|e| Error::config(format!("invalid server {}: {}", self.server, e)))?;
+        url.path_segments_mut()
+            .map_err(|_| Error::config(format!("invalid server {}", self.server)))?
+            .pop_if_empty()
+            .extend(&[
+                "admin",
+                &self.namespace,
+                &self.name,
+                &self.endpoint,
+                self.action.as_str(),
+            ]);
+        if let Some(ttl) = self.ttl {
+            url.query_pairs_mut().append_pair("ttl", &ttl.to_string());
+        }
+        let resp = reqwest::Client::new().post(url).send().await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if !status.is_success() {
+            return Err(Error::config(format!("{}: {}", status, body.trim())));
+        }
+        println!("{}", body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    // a service of one address, so that removing it touches nothing in k8s
+    async fn services() -> Services {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP";
+        let svc = kube::Service::new(
+            yml_str.to_owned(),
+            kube::Threshold {
+                restore: 1,
+                remove: 1,
+            },
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        let svcs: Services = Arc::new(RwLock::new(HashMap::new()));
+        svcs.write()
+            .await
+            .insert(svc.key(), Arc::new(RwLock::new(svc)));
+        svcs
+    }
+
+    #[tokio::test]
+    async fn drain_and_release() {
+        let svcs = services().await;
+        let svc = apply(&svcs, "default", "api", "172.0.1.4", Action::Drain, None)
+            .await
+            .unwrap();
+        assert_eq!(svc.endpoints[0].status, "removed");
+        assert_eq!(svc.endpoints[0].admin, Some("drained"));
+
+        let svc = apply(
+            &svcs,
+            "default",
+            "api",
+            "172.0.1.4:80",
+            Action::Release,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(svc.endpoints[0].admin.is_none());
+        // releasing leaves it to probes to restore
+        assert_eq!(svc.endpoints[0].status, "removed");
+
+        let err = apply(&svcs, "default", "api", "172.0.1.5", Action::Drain, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), crate::error::ErrorKind::NotFound));
+        let err = apply(&svcs, "default", "web", "172.0.1.4", Action::Drain, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), crate::error::ErrorKind::NotFound));
+        assert!(apply(&svcs, "default", "api", "bogus", Action::Drain, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_failed() {
+        // two addresses, so that draining one is written to k8s, which is
+        //  nowhere to be found
+        let svcs = services().await;
+        {
+            let svc = svcs.read().await.values().next().unwrap().clone();
+            let mut svc = svc.write().await;
+            let mut ep = svc.endpoints[0].clone();
+            ep.addr = "172.0.1.5:80".parse().unwrap();
+            svc.endpoints.push(ep);
+            let mut addr = svc.repr.subsets[0].addresses[0].clone();
+            addr.ip = "172.0.1.5".to_owned();
+            svc.repr.subsets[0].addresses.push(addr);
+        }
+        assert!(
+            apply(&svcs, "default", "api", "172.0.1.4", Action::Drain, None)
+                .await
+                .is_err()
+        );
+        let svc = svcs.read().await.values().next().unwrap().clone();
+        let svc = svc.read().await;
+        assert!(svc.endpoints[0].admin.is_none());
+        assert_eq!(svc.endpoints[0].status, EndpointStatus::Healthy);
+    }
+
+    #[tokio::test]
+    async fn restore_gone() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+            targetRef: {kind: Pod, namespace: default, name: api-0, uid: \"0\"}
+          - ip: 172.0.1.5
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP";
+        // knows of no pod
+        let mut client = kube::tests::api_server(|_, _| ("404 Not Found", "{}".to_owned())).await;
+        client.dry_run = true;
+        let client = Arc::new(client);
+        let new = |yml_str: String| {
+            kube::Service::new(
+                yml_str,
+                Default::default(),
+                Arc::new(crate::alert::Alert::default()),
+                client.clone(),
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let mut svc = new(yml_str.to_owned());
+        svc.remove_ep(0).await.unwrap();
+        let svc = new(svc.repr.to_yaml().unwrap());
+        let svcs: Services = Arc::new(RwLock::new(HashMap::new()));
+        svcs.write()
+            .await
+            .insert(svc.key(), Arc::new(RwLock::new(svc)));
+
+        // forgotten rather than restored, the one after it is left alone
+        let svc = apply(&svcs, "default", "api", "172.0.1.4", Action::Restore, None)
+            .await
+            .unwrap();
+        assert_eq!(svc.endpoints.len(), 1);
+        assert!(svc.endpoints[0].admin.is_none());
+    }
+
+    #[test]
+    fn admin_override() {
+        let now = Instant::now();
+        let mut admin = Admin {
+            state: Override::Restored,
+            until: Some(now + Duration::from_secs(1)),
+        };
+        let mut ep = kube::Endpoint {
+            addr: "127.0.0.1:80".parse().unwrap(),
+            protocol: kube::Protocol::TCP,
+            port_name: None,
+            app_protocol: None,
+            subset: 0,
+            status: EndpointStatus::Healthy,
+            counter: kube::Counter { up: 0, down: 0 },
+            threshold: Default::default(),
+            probe_state: Default::default(),
+            admin: Some(admin.clone()),
+        };
+        assert_eq!(ep.admin_override(now), Some(Override::Restored));
+        assert_eq!(ep.admin_override(now + Duration::from_secs(1)), None);
+        assert!(ep.admin.is_none());
+
+        admin.until = None;
+        ep.admin = Some(admin);
+        assert_eq!(
+            ep.admin_override(now + Duration::from_secs(3600)),
+            Some(Override::Restored)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use log::debug;
+use serde_json::{json, Value};
+use url::Url;
+
+use super::{AlertChannel, Level, Msg};
+use crate::error::{Error, Result};
+
+// DingTalk robot, messages are markdown
+pub struct DingTalk {
+    http: reqwest::Client,
+    url: Url,
+    secret: Option<String>,
+}
+
+impl DingTalk {
+    pub fn new(url: &str) -> Result<Self> {
+        let (url, secret) = super::split_secret(url)?;
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url,
+            secret,
+        })
+    }
+
+    // with the timestamp in milliseconds and the signature of it in the
+    //  query if signing is enabled
+    fn signed_url(&self, timestamp: u128) -> Url {
+        let mut url = self.url.clone();
+        if let Some(secret) = &self.secret {
+            let sign = super::hmac_sha256(
+                secret.as_bytes(),
+                format!("{}\n{}", timestamp, secret).as_bytes(),
+            );
+            url.query_pairs_mut()
+                .append_pair("timestamp", &timestamp.to_string())
+                .append_pair("sign", &sign);
+        }
+        url
+    }
+}
+
+#[async_trait]
+impl AlertChannel for DingTalk {
+    fn name(&self) -> &'static str {
+        "dingtalk"
+    }
+
+    async fn send(&self, msg: &Msg) -> Result<()> {
+        let msg = payload(msg, super::cluster_name(), crate::CFG.dry_run).to_string();
+        debug!("sending alert to dingtalk, message: {}", &msg);
+        let url = self.signed_url(super::unix_time().as_millis());
+        let resp = super::post_json(&self.http, url, msg).await?;
+        // errors such as a bad signature come with a 200
+        let resp: Value = serde_json::from_str(&resp)?;
+        match resp["errcode"].as_i64() {
+            Some(0) => Ok(()),
+            _ => Err(Error::alert(format!(
+                "dingtalk refused the message: {}",
+                resp
+            ))),
+        }
+    }
+}
+
+fn payload(msg: &Msg, cluster_name: &str, dry_run: bool) -> Value {
+    let card = msg.card(cluster_name, dry_run);
+    let color = match card.level {
+        Level::Good => "#2eb67d",
+        Level::Bad => "#e01e5a",
+        Level::Warning => "#ecb22e",
+    };
+    let mut lines = vec![format!(
+        "#### <font color=\"{}\">{}</font>",
+        color, card.title
+    )];
+    lines.extend(
+        card.fields
+            .iter()
+            .map(|(name, value)| format!("- **{}**: {}", name, value)),
+    );
+    lines.extend(card.details.iter().map(|line| format!("> {}", line)));
+    json!({
+        "msgtype": "markdown",
+        "markdown": {
+            // shown in notifications
+            "title": card.title,
+            "text": lines.join("\n\n"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_url() {
+        let robot =
+            DingTalk::new("https://oapi.dingtalk.com/robot/send?access_token=abc&secret=SEC1")
+                .unwrap();
+        assert_eq!(
+            robot.signed_url(1700000000000).as_str(),
+            "https://oapi.dingtalk.com/robot/send?access_token=abc\
+            &timestamp=1700000000000&sign=ukR4%2BWoOOBTGaOauYqugxVrMSFyr92q4hPxJxuOkiio%3D"
+        );
+        let robot = DingTalk::new("https://oapi.dingtalk.com/robot/send?access_token=abc").unwrap();
+        assert_eq!(
+            robot.signed_url(1700000000000).as_str(),
+            "https://oapi.dingtalk.com/robot/send?access_token=abc"
+        );
+    }
+
+    #[test]
+    fn payload() {
+        let msg = Msg::EpDown(
+            "default".to_owned(),
+            "api".to_owned(),
+            "10.0.0.1:80".to_owned(),
+        );
+        let payload = super::payload(&msg, "prod", false);
+        assert_eq!(payload["msgtype"], "markdown");
+        assert_eq!(payload["markdown"]["title"], "☠ ENDPOINT DOWN");
+        assert_eq!(
+            payload["markdown"]["text"],
+            "#### <font color=\"#e01e5a\">☠ ENDPOINT DOWN</font>\n\n\
+            - **Cluster**: prod\n\n\
+            - **Namespace**: default\n\n\
+            - **Service**: api\n\n\
+            - **Endpoint**: 10.0.0.1:80"
+        );
+    }
+}
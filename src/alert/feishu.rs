@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use log::debug;
+use serde_json::{json, Value};
+use url::Url;
+
+use super::{AlertChannel, Level, Msg};
+use crate::error::{Error, Result};
+
+// Feishu, or Lark, robot, messages are interactive cards
+pub struct Feishu {
+    http: reqwest::Client,
+    url: Url,
+    secret: Option<String>,
+}
+
+impl Feishu {
+    pub fn new(url: &str) -> Result<Self> {
+        let (url, secret) = super::split_secret(url)?;
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url,
+            secret,
+        })
+    }
+
+    // with the timestamp in seconds and the signature of it in the body if
+    //  signing is enabled, the signature being that of nothing under a key
+    //  made of both
+    fn sign(&self, payload: &mut Value, timestamp: u64) {
+        if let Some(secret) = &self.secret {
+            let key = format!("{}\n{}", timestamp, secret);
+            payload["timestamp"] = json!(timestamp.to_string());
+            payload["sign"] = json!(super::hmac_sha256(key.as_bytes(), b""));
+        }
+    }
+}
+
+#[async_trait]
+impl AlertChannel for Feishu {
+    fn name(&self) -> &'static str {
+        "feishu"
+    }
+
+    async fn send(&self, msg: &Msg) -> Result<()> {
+        let mut msg = payload(msg, super::cluster_name(), crate::CFG.dry_run);
+        self.sign(&mut msg, super::unix_time().as_secs());
+        let msg = msg.to_string();
+        debug!("sending alert to feishu, message: {}", &msg);
+        let resp = super::post_json(&self.http, self.url.clone(), msg).await?;
+        // errors such as a bad signature come with a 200, older robots
+        //  answer with StatusCode
+        let resp: Value = serde_json::from_str(&resp)?;
+        match resp["code"]
+            .as_i64()
+            .or_else(|| resp["StatusCode"].as_i64())
+        {
+            Some(0) => Ok(()),
+            _ => Err(Error::alert(format!(
+                "feishu refused the message: {}",
+                resp
+            ))),
+        }
+    }
+}
+
+fn payload(msg: &Msg, cluster_name: &str, dry_run: bool) -> Value {
+    let card = msg.card(cluster_name, dry_run);
+    let template = match card.level {
+        Level::Good => "green",
+        Level::Bad => "red",
+        Level::Warning => "orange",
+    };
+    let fields: Vec<Value> = card
+        .fields
+        .iter()
+        .map(|(name, value)| {
+            json!({
+                "is_short": true,
+                "text": {"tag": "lark_md", "content": format!("**{}**\n{}", name, value)},
+            })
+        })
+        .collect();
+    let mut elements = vec![json!({"tag": "div", "fields": fields})];
+    if !card.details.is_empty() {
+        elements.push(json!({
+            "tag": "div",
+            "text": {"tag": "plain_text", "content": card.details.join("\n")},
+        }));
+    }
+    json!({
+        "msg_type": "interactive",
+        "card": {
+            "config": {"wide_screen_mode": true},
+            "header": {
+                "template": template,
+                "title": {"tag": "plain_text", "content": card.title},
+            },
+            "elements": elements,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign() {
+        let robot =
+            Feishu::new("https://open.feishu.cn/open-apis/bot/v2/hook/abc?secret=SEC1").unwrap();
+        assert_eq!(
+            robot.url.as_str(),
+            "https://open.feishu.cn/open-apis/bot/v2/hook/abc"
+        );
+        let mut payload = json!({"msg_type": "interactive"});
+        robot.sign(&mut payload, 1700000000);
+        assert_eq!(payload["timestamp"], "1700000000");
+        assert_eq!(
+            payload["sign"],
+            "s3Va4ru9IQUY6HTJcYx7OEVyXl0V0S/y32v4ZbfOHCI="
+        );
+
+        let robot = Feishu::new("https://open.feishu.cn/open-apis/bot/v2/hook/abc").unwrap();
+        let mut payload = json!({"msg_type": "interactive"});
+        robot.sign(&mut payload, 1700000000);
+        assert!(payload.get("sign").is_none());
+    }
+
+    #[test]
+    fn payload() {
+        let msg = Msg::InvalidAnnotations(
+            "default".to_owned(),
+            "api".to_owned(),
+            vec!["ephc.io/timeout-ms=\"x\": invalid digit".to_owned()],
+        );
+        let payload = super::payload(&msg, "prod", true);
+        let card = &payload["card"];
+        assert_eq!(card["header"]["template"], "orange");
+        assert_eq!(
+            card["header"]["title"]["content"],
+            "[DRY RUN] ⚠ INVALID ANNOTATIONS IGNORED"
+        );
+        assert_eq!(card["elements"][0]["fields"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            card["elements"][0]["fields"][2]["text"]["content"],
+            "**Service**\napi"
+        );
+        assert_eq!(
+            card["elements"][1]["text"]["content"],
+            "ephc.io/timeout-ms=\"x\": invalid digit"
+        );
+    }
+}
@@ -1,106 +1,392 @@
 use async_trait::async_trait;
-use log::error;
+use hmac::{Hmac, Mac};
+use log::{debug, error};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{self, Duration};
+use url::Url;
 
+use crate::error::{Error, Result};
+
+pub mod dingtalk;
+pub mod feishu;
+pub mod slack;
 pub mod wecom;
 
+// how long a channel is given to send a message
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[async_trait]
 pub trait AlertChannel {
-    async fn send(&self, msg: Msg);
+    // of the kind of channel, for logs, which urls with tokens are kept out of
+    fn name(&self) -> &'static str;
+
+    async fn send(&self, msg: &Msg) -> Result<()>;
 }
 
 pub enum Msg {
-    // service name, ep addr
-    EpDown(String, String),
-    // service name, ep addr
-    EpUp(String, String),
-    // service name
-    AllEpDown(String),
+    // namespace, service name, ep addr
+    EpDown(String, String, String),
+    // namespace, service name, ep addr
+    EpUp(String, String, String),
+    // namespace, service name
+    AllEpDown(String, String),
+    // namespace, service name, why the annotations are ignored
+    InvalidAnnotations(String, String, Vec<String>),
 }
 
-impl Msg {
-    fn to_string(&self) -> String {
-        let default_cluster_name = &"unknown".to_owned();
-        let cluster_name = crate::CFG
-            .cluster_name
-            .as_ref()
-            .unwrap_or(default_cluster_name);
+// Lines of the message as plain text, for channels to escape as they need
+impl std::fmt::Display for Msg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cluster_name = cluster_name();
+        if crate::CFG.dry_run {
+            write!(f, "[DRY RUN] ")?;
+        }
         match self {
-            Msg::EpDown(svc, addr) => {
-                format!(
-                    r#"☠ ENDPOINT DOWN\nCluster: {}\nService: {}\nendpoint: {}"#,
-                    cluster_name, svc, addr
+            Msg::EpDown(ns, svc, addr) => {
+                write!(
+                    f,
+                    "☠ ENDPOINT DOWN\nCluster: {}\nNamespace: {}\nService: {}\nendpoint: {}",
+                    cluster_name, ns, svc, addr
                 )
             }
-            Msg::EpUp(svc, addr) => {
-                format!(
-                    r#"👍 ENDPOINT UP\nCluster: {}\nService: {}\nendpoint: {}"#,
-                    cluster_name, svc, addr
+            Msg::EpUp(ns, svc, addr) => {
+                write!(
+                    f,
+                    "👍 ENDPOINT UP\nCluster: {}\nNamespace: {}\nService: {}\nendpoint: {}",
+                    cluster_name, ns, svc, addr
                 )
             }
-            Msg::AllEpDown(svc) => format!(
-                r#"☠☠☠ ALL ENDPOINTS DOWN\nCluster: {}\nService: {}"#,
-                cluster_name, svc
+            Msg::AllEpDown(ns, svc) => write!(
+                f,
+                "☠☠☠ ALL ENDPOINTS DOWN\nCluster: {}\nNamespace: {}\nService: {}",
+                cluster_name, ns, svc
+            ),
+            Msg::InvalidAnnotations(ns, svc, errors) => write!(
+                f,
+                "⚠ INVALID ANNOTATIONS IGNORED\nCluster: {}\nNamespace: {}\nService: {}\n{}",
+                cluster_name,
+                ns,
+                svc,
+                errors.join("\n")
+            ),
+        }
+    }
+}
+
+// how good or bad the news of a message is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Good,
+    Bad,
+    Warning,
+}
+
+// a message in parts, for the channels with rich formats to lay out
+pub struct Card<'a> {
+    pub title: String,
+    pub level: Level,
+    // name and value, the cluster first
+    pub fields: Vec<(&'static str, &'a str)>,
+    // lines that fit in no field
+    pub details: &'a [String],
+}
+
+impl Msg {
+    pub fn card<'a>(&'a self, cluster_name: &'a str, dry_run: bool) -> Card<'a> {
+        let (title, level, ns, svc, endpoint, details) = match self {
+            Msg::EpDown(ns, svc, addr) => {
+                ("☠ ENDPOINT DOWN", Level::Bad, ns, svc, Some(addr), &[][..])
+            }
+            Msg::EpUp(ns, svc, addr) => {
+                ("👍 ENDPOINT UP", Level::Good, ns, svc, Some(addr), &[][..])
+            }
+            Msg::AllEpDown(ns, svc) => {
+                ("☠☠☠ ALL ENDPOINTS DOWN", Level::Bad, ns, svc, None, &[][..])
+            }
+            Msg::InvalidAnnotations(ns, svc, errors) => (
+                "⚠ INVALID ANNOTATIONS IGNORED",
+                Level::Warning,
+                ns,
+                svc,
+                None,
+                &errors[..],
             ),
+        };
+        let title = if dry_run {
+            format!("[DRY RUN] {}", title)
+        } else {
+            title.to_owned()
+        };
+        let mut fields = vec![
+            ("Cluster", cluster_name),
+            ("Namespace", ns.as_str()),
+            ("Service", svc.as_str()),
+        ];
+        if let Some(endpoint) = endpoint {
+            fields.push(("Endpoint", endpoint.as_str()));
+        }
+        Card {
+            title,
+            level,
+            fields,
+            details,
         }
     }
 }
 
+fn cluster_name() -> &'static str {
+    crate::CFG.cluster_name.as_deref().unwrap_or("unknown")
+}
+
+// Robots of DingTalk and Feishu sign messages with a secret when asked to,
+//  which is given as the secret query parameter of the url and not sent along
+fn split_secret(url: &str) -> Result<(Url, Option<String>)> {
+    let mut url =
+        Url::parse(url).map_err(|e| Error::config(format!("invalid alert url {}: {}", url, e)))?;
+    let mut secret = None;
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter_map(|(k, v)| {
+            if k == "secret" {
+                secret = Some(v.into_owned());
+                None
+            } else {
+                Some((k.into_owned(), v.into_owned()))
+            }
+        })
+        .collect();
+    url.set_query(None);
+    if !pairs.is_empty() {
+        url.query_pairs_mut().extend_pairs(pairs);
+    }
+    Ok((url, secret))
+}
+
+// base64 of the HMAC-SHA256 of msg
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> String {
+    // any size of key will do
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac key");
+    mac.update(msg);
+    base64::encode(mac.finalize().into_bytes())
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Alerts go to every channel at once
+#[derive(Default)]
 pub struct Alert {
-    channel: Option<Box<dyn AlertChannel + Send + Sync>>,
+    channels: Vec<Arc<dyn AlertChannel + Send + Sync>>,
 }
 
 impl Alert {
-    // pub fn new(channel: Option<Box<dyn AlertChannel + Send + Sync>>) -> Self {
-    //     Self { channel }
-    // }
+    // urls in the form of scheme://url, none for no alerting
+    pub fn from_urls(urls: &[String]) -> Result<Self> {
+        let channels = urls
+            .iter()
+            .filter(|url| !url.is_empty())
+            .map(|url| channel(url))
+            .collect::<Result<_>>()?;
+        Ok(Self { channels })
+    }
 
-    pub fn from_url_scheme(url: &Option<String>) -> Self {
-        if url.is_none() {
-            return Self { channel: None };
-        }
-        let url = url.as_ref().unwrap();
-        if url == "" {
-            return Self { channel: None };
+    // Sending goes on in the background, callers hold the lock of a service
+    //  and aren't to wait for any channel
+    pub fn alert(&self, msg: Msg) {
+        if self.channels.is_empty() {
+            return;
         }
-        let mut url_parts = url.split("://");
-        if let Some(scheme) = url_parts.nth(0) {
-            let realurl: String = url_parts.collect::<Vec<&str>>().join("://");
-            return match scheme {
-                "wecom" => {
-                    let channel = wecom::WeCom::new(realurl);
-                    Self {
-                        channel: Some(Box::new(channel)),
+        tokio::spawn(send_all(self.channels.clone(), msg));
+    }
+}
+
+// Each channel is sent msg on its own and given SEND_TIMEOUT, so that a slow
+//  or failing one holds up or fails none of the others
+async fn send_all(channels: Vec<Arc<dyn AlertChannel + Send + Sync>>, msg: Msg) {
+    let msg = Arc::new(msg);
+    let jhs: Vec<_> = channels
+        .iter()
+        .map(|channel| {
+            let (channel, msg) = (channel.clone(), msg.clone());
+            tokio::spawn(async move {
+                match time::timeout(SEND_TIMEOUT, channel.send(&msg)).await {
+                    Ok(Ok(())) => debug!("alert message sent to {}", channel.name()),
+                    Ok(Err(e)) => {
+                        error!("failed to send alert message to {}: {}", channel.name(), e)
                     }
+                    Err(_) => error!("timed out sending alert message to {}", channel.name()),
                 }
-                _ => {
-                    error!("unknown alert channel {}", scheme);
-                    return Self { channel: None };
-                }
-            };
-        } else {
-            error!("Invalid alert url: {}, see help", url);
-            return Self { channel: None };
-        }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        let _ = jh.await;
     }
+}
 
-    pub async fn alert(&self, msg: Msg) {
-        if self.channel.is_none() {
-            return;
-        }
-        self.channel.as_ref().unwrap().send(msg).await
+// body posted to url as json, and what comes back. The url holds the token
+//  of the webhook, so it's kept out of the errors which end up in logs.
+async fn post_json<U: reqwest::IntoUrl>(
+    http: &reqwest::Client,
+    url: U,
+    body: String,
+) -> Result<String> {
+    let resp = http
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.without_url())?;
+    Ok(resp.text().await.map_err(|e| e.without_url())?)
+}
+
+fn channel(url: &str) -> Result<Arc<dyn AlertChannel + Send + Sync>> {
+    let (scheme, realurl) = url.split_once("://").ok_or_else(|| {
+        Error::config(format!(
+            "invalid alert url {}, not in the form of scheme://url",
+            url
+        ))
+    })?;
+    match scheme {
+        "wecom" => Ok(Arc::new(wecom::WeCom::new(realurl.to_owned()))),
+        "slack" => Ok(Arc::new(slack::Slack::new(realurl.to_owned()))),
+        "dingtalk" => Ok(Arc::new(dingtalk::DingTalk::new(realurl)?)),
+        "feishu" => Ok(Arc::new(feishu::Feishu::new(realurl)?)),
+        _ => Err(Error::config(format!(
+            "unknown alert channel {} of {}",
+            scheme, url
+        ))),
     }
 }
 
 impl std::fmt::Debug for Alert {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Alert")
-            .field("channel", &"".to_owned())
-            .finish()
+        let names: Vec<&str> = self.channels.iter().map(|c| c.name()).collect();
+        f.debug_struct("Alert").field("channels", &names).finish()
     }
 }
 
-impl std::default::Default for Alert {
-    fn default() -> Self {
-        Self { channel: None }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Fake {
+        delay: Duration,
+        fail: bool,
+        sent: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AlertChannel for Fake {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn send(&self, _msg: &Msg) -> Result<()> {
+            time::sleep(self.delay).await;
+            if self.fail {
+                return Err(Error::new("failed"));
+            }
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?"),
+            "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM="
+        );
+
+        let (url, secret) =
+            split_secret("https://example.com/send?access_token=abc&secret=SEC1").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/send?access_token=abc");
+        assert_eq!(secret.as_deref(), Some("SEC1"));
+        let (url, secret) = split_secret("https://example.com/hook/abc?secret=SEC1").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/hook/abc");
+        assert_eq!(secret.as_deref(), Some("SEC1"));
+        let (_, secret) = split_secret("https://example.com/hook/abc").unwrap();
+        assert!(secret.is_none());
+        assert!(split_secret("example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn post_json_without_url() {
+        let http = reqwest::Client::new();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        });
+        let url = format!("http://{}/send?key=SECRET", addr);
+        let e = post_json(&http, &url, "{}".to_owned()).await.unwrap_err();
+        assert!(e.to_string().contains("500"), "{}", e);
+        assert!(!e.to_string().contains("SECRET"), "{}", e);
+
+        // nothing listens any more
+        let e = post_json(&http, &url, "{}".to_owned()).await.unwrap_err();
+        assert!(!e.to_string().contains("SECRET"), "{}", e);
+    }
+
+    #[test]
+    fn from_urls() {
+        let alert = Alert::from_urls(&[
+            "wecom://https://example.com/1".to_owned(),
+            "".to_owned(),
+            "slack://https://hooks.slack.com/services/T/B/X".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(alert.channels.len(), 2);
+        assert!(Alert::from_urls(&[]).unwrap().channels.is_empty());
+        assert!(Alert::from_urls(&["https://example.com".to_owned()]).is_err());
+        assert!(Alert::from_urls(&["teams://https://example.com".to_owned()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let fake = |delay, fail| -> Arc<dyn AlertChannel + Send + Sync> {
+            Arc::new(Fake {
+                delay: Duration::from_millis(delay),
+                fail,
+                sent: sent.clone(),
+            })
+        };
+        let alert = Alert {
+            channels: vec![fake(200, false), fake(0, true), fake(200, false)],
+        };
+        let start = std::time::Instant::now();
+        send_all(
+            alert.channels.clone(),
+            Msg::AllEpDown("default".to_owned(), "api".to_owned()),
+        )
+        .await;
+        // the failing one stops none of the others, and the slow ones are
+        //  waited for together
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // none is waited for by the caller
+        let start = std::time::Instant::now();
+        alert.alert(Msg::AllEpDown("default".to_owned(), "api".to_owned()));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 4);
     }
 }
@@ -0,0 +1,132 @@
+use async_trait::async_trait;
+use log::debug;
+use serde_json::{json, Value};
+
+use super::{AlertChannel, Level, Msg};
+use crate::error::Result;
+
+const RED: &str = "#e01e5a";
+const GREEN: &str = "#2eb67d";
+const YELLOW: &str = "#ecb22e";
+
+// Slack incoming webhook, messages are Block Kit blocks in an attachment
+//  coloured by how bad the news is
+pub struct Slack {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Slack {
+    pub fn new(url: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl AlertChannel for Slack {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    async fn send(&self, msg: &Msg) -> Result<()> {
+        let msg = payload(msg, super::cluster_name(), crate::CFG.dry_run).to_string();
+        debug!("sending alert to slack, message: {}", &msg);
+        super::post_json(&self.http, &self.url, msg).await?;
+        Ok(())
+    }
+}
+
+fn payload(msg: &Msg, cluster_name: &str, dry_run: bool) -> Value {
+    let card = msg.card(cluster_name, dry_run);
+    let color = match card.level {
+        Level::Good => GREEN,
+        Level::Bad => RED,
+        Level::Warning => YELLOW,
+    };
+    let fields: Vec<Value> = card
+        .fields
+        .iter()
+        .map(|(name, value)| field(name, value))
+        .collect();
+    let mut blocks = vec![
+        json!({"type": "section", "text": {"type": "mrkdwn", "text": format!("*{}*", escape(&card.title))}}),
+        json!({"type": "section", "fields": fields}),
+    ];
+    if !card.details.is_empty() {
+        blocks.push(json!({
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": format!("```{}```", escape(&card.details.join("\n")))},
+        }));
+    }
+    json!({
+        // shown in notifications, which blocks are not
+        "text": card.title,
+        "attachments": [{"color": color, "blocks": blocks}],
+    })
+}
+
+fn field(name: &str, value: &str) -> Value {
+    json!({"type": "mrkdwn", "text": format!("*{}*\n{}", name, escape(value))})
+}
+
+// the only characters mrkdwn wants escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        let msg = Msg::EpDown(
+            "default".to_owned(),
+            "api".to_owned(),
+            "10.0.0.1:80".to_owned(),
+        );
+        let payload = super::payload(&msg, "prod", false);
+        assert_eq!(payload["text"], "☠ ENDPOINT DOWN");
+        let attachment = &payload["attachments"][0];
+        assert_eq!(attachment["color"], RED);
+        let fields: Vec<_> = attachment["blocks"][1]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "*Cluster*\nprod",
+                "*Namespace*\ndefault",
+                "*Service*\napi",
+                "*Endpoint*\n10.0.0.1:80"
+            ]
+        );
+
+        let msg = Msg::AllEpDown("default".to_owned(), "api".to_owned());
+        let payload = super::payload(&msg, "prod", true);
+        assert_eq!(payload["text"], "[DRY RUN] ☠☠☠ ALL ENDPOINTS DOWN");
+        let blocks = &payload["attachments"][0]["blocks"];
+        assert_eq!(blocks[1]["fields"].as_array().unwrap().len(), 3);
+
+        let msg = Msg::InvalidAnnotations(
+            "default".to_owned(),
+            "api".to_owned(),
+            vec!["ephc.io/timeout-ms=\"<1\": invalid digit".to_owned()],
+        );
+        let payload = super::payload(&msg, "prod", false);
+        let attachment = &payload["attachments"][0];
+        assert_eq!(attachment["color"], YELLOW);
+        assert_eq!(
+            attachment["blocks"][2]["text"]["text"],
+            "```ephc.io/timeout-ms=\"&lt;1\": invalid digit```"
+        );
+    }
+}
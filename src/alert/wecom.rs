@@ -1,8 +1,9 @@
 use async_trait::async_trait;
-use log::{debug, error};
-use reqwest;
+use log::debug;
+use serde_json::{json, Value};
 
 use super::{AlertChannel, Msg};
+use crate::error::Result;
 
 pub struct WeCom {
     http: reqwest::Client,
@@ -18,22 +19,38 @@ impl WeCom {
     }
 }
 
+// a text message, the content is escaped as any JSON string
+fn payload(content: &str) -> Value {
+    json!({
+        "msgtype": "text",
+        "text": {"content": content},
+    })
+}
+
 #[async_trait]
 impl AlertChannel for WeCom {
-    async fn send(&self, msg: Msg) {
-        let msg = format!(
-            r#"{{
-                "msgtype": "text",
-                "text": {{
-                    "content":"{}"
-                }}
-            }}"#,
-            msg.to_string()
-        );
-        debug!("sending alert to {}, message: {}", &self.url, &msg);
-        match self.http.post(&self.url).body(msg).send().await {
-            Err(e) => error!("failed to send alert message: {}", e),
-            _ => debug!("alert message sent"),
-        };
+    fn name(&self) -> &'static str {
+        "wecom"
+    }
+
+    async fn send(&self, msg: &Msg) -> Result<()> {
+        let msg = payload(&msg.to_string()).to_string();
+        debug!("sending alert to {}, message: {}", self.name(), &msg);
+        super::post_json(&self.http, &self.url, msg).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_escaped() {
+        let content = "⚠ INVALID ANNOTATIONS IGNORED\nephc.io/path=\"a\\b\": bad";
+        let payload = payload(content).to_string();
+        let parsed: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(parsed["msgtype"], "text");
+        assert_eq!(parsed["text"]["content"], content);
     }
 }
@@ -1,31 +1,100 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::net::SocketAddr;
+use std::str::FromStr;
 
+use crate::admin::{self, Action};
+use crate::alert::Alert;
+use crate::kube::Backend;
+use crate::probe::{ProbeRule, Probes};
+
+const DEFAULT_BACKEND: &str = "endpoints";
 const DEFAULT_REFRESH_INTERVAL: &str = "1";
 const DEFAULT_PROBE_INTERVAL: &str = "1000";
+const DEFAULT_PROBE_JITTER: &str = "10";
 const DEFAULT_CONNECT_TIMEOUT: &str = "100";
+const DEFAULT_MAX_PROBES: &str = "256";
+const DEFAULT_MAX_SERVICE_PROBES: &str = "32";
 const DEFAULT_RESTORE: &str = "3";
 const DEFAULT_REMOVE: &str = "3";
+const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+const DEFAULT_SHUTDOWN_TIMEOUT: &str = "10";
+// the admin API can drain any endpoint, it's only reachable from the pod
+//  unless asked otherwise
+const DEFAULT_ADMIN_LISTEN: &str = "127.0.0.1:8081";
+const DEFAULT_SERVER: &str = "http://127.0.0.1:8081";
 
 #[derive(Debug, Clone)]
 pub struct AppOpt {
+    pub namespaces: Option<Vec<String>>,
+    pub all_namespaces: bool,
     pub allow_list: Option<Vec<String>>,
     pub block_list: Option<Vec<String>>,
+    pub backend: Backend,
     pub refresh_interval: u64,
+    pub watch: bool,
     pub probe_interval: u64,
+    pub removed_probe_interval: u64,
+    pub max_removed_probe_interval: u64,
+    pub probe_jitter: u32,
     pub connection_timeout: u64,
+    pub probes: Probes,
+    pub max_probes: usize,
+    pub max_service_probes: usize,
     pub restore: u32,
     pub remove: u32,
     pub cluster_name: Option<String>,
-    pub alert_channel: Option<String>,
+    pub alert_channels: Vec<String>,
+    pub listen: SocketAddr,
+    pub admin_listen: SocketAddr,
+    pub dry_run: bool,
+    pub shutdown_timeout: u64,
+    // path of the config file
+    pub config: Option<String>,
+    // set if ephc is run to send a request to the admin API of another one
+    pub admin: Option<admin::Command>,
+    // set if ephc is run to check a config file
+    pub check_config: Option<String>,
 }
 
+// Every problem with the options is reported before exiting, not only the
+//  first one
 pub(crate) fn init() -> AppOpt {
     env_logger::init();
 
-    let matches = App::new("ephc")
+    match from_matches(&app().get_matches()) {
+        Ok(opt) => opt,
+        Err(problems) => {
+            for problem in problems {
+                eprintln!("error: {}", problem);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("ephc")
         .version("0.1")
         .author("pan1c <qiang@pan1c.org>")
         .about("Endpoint health check for Kubernetes")
+        .arg(
+            Arg::with_name("namespace")
+                .short("n")
+                .long("namespace")
+                .value_name("NAMESPACE")
+                .required(false)
+                .multiple(true)
+                .takes_value(true)
+                .help("Do health check for services in these namespaces, defaults to the namespace of the current context"),
+        )
+        .arg(
+            Arg::with_name("all_namespaces")
+                .long("all-namespaces")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("namespace")
+                .help("Do health check for services in all namespaces"),
+        )
         .arg(
             Arg::with_name("allow_list")
                 .short("a")
@@ -34,7 +103,7 @@ pub(crate) fn init() -> AppOpt {
                 .value_name("ALLOW")
                 .multiple(true)
                 .takes_value(true)
-                .help("Only do health check for these services"),
+                .help("Only do health check for these services, in the form of NAME or NAMESPACE/NAME"),
         )
         .arg(
             Arg::with_name("block_list")
@@ -44,7 +113,17 @@ pub(crate) fn init() -> AppOpt {
                 .required(false)
                 .multiple(true)
                 .takes_value(true)
-                .help("Do health check for all services except these"),
+                .help("Do health check for all services except these, in the form of NAME or NAMESPACE/NAME"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["endpoints", "endpointslices"])
+                .default_value(DEFAULT_BACKEND)
+                .help("Read and update endpoints from Endpoints or EndpointSlices(discovery.k8s.io/v1)"),
         )
         .arg(
             Arg::with_name("refresh_interval")
@@ -56,6 +135,14 @@ pub(crate) fn init() -> AppOpt {
                 .default_value(DEFAULT_REFRESH_INTERVAL)
                 .help("Interval in seconds to refresh service from k8s"),
         )
+        .arg(
+            Arg::with_name("watch")
+                .short("w")
+                .long("watch")
+                .required(false)
+                .takes_value(false)
+                .help("Watch services and endpoints for changes instead of refreshing them every refresh_interval"),
+        )
         .arg(
             Arg::with_name("probe_interval")
                 .short("p")
@@ -64,7 +151,36 @@ pub(crate) fn init() -> AppOpt {
                 .required(false)
                 .takes_value(true)
                 .default_value(DEFAULT_PROBE_INTERVAL)
-                .help("Interval in milliseconds to probe services"),
+                .help("Interval in milliseconds to probe each endpoint"),
+        )
+        .arg(
+            Arg::with_name("removed_probe_interval")
+                .long("removed-probe-interval")
+                .value_name("REMOVED_PROBE_INTERVAL")
+                .required(false)
+                .takes_value(true)
+                .help("Interval in milliseconds to probe each removed endpoint, defaults to PROBE_INTERVAL"),
+        )
+        .arg(
+            Arg::with_name("max_removed_probe_interval")
+                .long("max-removed-probe-interval")
+                .value_name("MAX_REMOVED_PROBE_INTERVAL")
+                .required(false)
+                .takes_value(true)
+                .help(
+                    "Back off probing a removed endpoint by doubling REMOVED_PROBE_INTERVAL \
+                    on every failed probe up to this many milliseconds, \
+                    defaults to REMOVED_PROBE_INTERVAL which means no backoff",
+                ),
+        )
+        .arg(
+            Arg::with_name("probe_jitter")
+                .long("probe-jitter")
+                .value_name("PROBE_JITTER")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_PROBE_JITTER)
+                .help("Percent of the interval each probe is randomly moved by, so that probes are spread out"),
         )
         .arg(
             Arg::with_name("connection_timeout")
@@ -74,7 +190,49 @@ pub(crate) fn init() -> AppOpt {
                 .required(false)
                 .takes_value(true)
                 .default_value(DEFAULT_CONNECT_TIMEOUT)
-                .help("Connection timeout in millisecond"),
+                .help("Timeout in millisecond of connecting to, or probing, an endpoint"),
+        )
+        .arg(
+            Arg::with_name("max_probes")
+                .long("max-probes")
+                .value_name("MAX_PROBES")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_MAX_PROBES)
+                .help("How many endpoints can be probed at the same time"),
+        )
+        .arg(
+            Arg::with_name("max_service_probes")
+                .long("max-service-probes")
+                .value_name("MAX_SERVICE_PROBES")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_MAX_SERVICE_PROBES)
+                .help("How many endpoints of a service can be probed at the same time"),
+        )
+        .arg(
+            Arg::with_name("probe")
+                .short("P")
+                .long("probe")
+                .value_name("PROBE")
+                .required(false)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .help(
+                    "Probe endpoints of SERVICE, or only its port PORT, other than by a TCP connect, \
+                    in the form of SERVICE[:PORT]=TYPE[,KEY=VALUE...], \
+                    SERVICE is NAME, NAMESPACE/NAME or *, PORT is a port name or number, \
+                    TYPE is tcp, http, https or grpc for TCP ports, udp or dns for UDP ports, \
+                    keys of http(s): method, path, host, status(200 or 200-399), body, regex, insecure, \
+                    key of grpc: service, \
+                    keys of udp: payload, hex(payload in hex), expect, regex, wait(milliseconds to wait for \
+                    an ICMP port unreachable if no response is expected, the probe timeout by default), \
+                    keys of dns: name(without which any response will do), type, \
+                    for example: web:http=http,path=/healthz,status=200, \
+                    by default ports named grpc or grpc-* or with appProtocol grpc are probed by grpc, \
+                    ports named dns or dns-* or numbered 53 by dns, other UDP ports by udp",
+                ),
         )
         .arg(
             Arg::with_name("remove")
@@ -103,7 +261,30 @@ pub(crate) fn init() -> AppOpt {
                 .value_name("CLUSTER")
                 .required(false)
                 .takes_value(true)
-                .help("cluster name of this kubernetes used with alert"),
+                .help("cluster name of this kubernetes used with alert and metrics"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("LISTEN")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_LISTEN)
+                .help("Address to serve metrics at /metrics and the status of services at /status on"),
+        )
+        .arg(
+            Arg::with_name("admin-listen")
+                .long("admin-listen")
+                .value_name("LISTEN")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_ADMIN_LISTEN)
+                .help(
+                    "Address to serve the admin API on, which drains and restores endpoints \
+                    without authentication, only bind it to an address others can reach \
+                    if they may do so",
+                ),
         )
         .arg(
             Arg::with_name("alert")
@@ -116,68 +297,409 @@ pub(crate) fn init() -> AppOpt {
                 .help(
                     "alert webhook url, in the form of scheme://url, \
                     for example: wecom://https://exmaple.com, \
+                    alerts go to every one given, \
                     supported channels:
-                        - wecom(and compatibles)",
+                        - wecom(and compatibles)
+                        - slack(incoming webhooks)
+                        - dingtalk(robots, signed with the secret query parameter if any)
+                        - feishu(and lark, robots, signed with the secret query parameter if any)",
+                ),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .required(false)
+                .takes_value(false)
+                .help("Probe and alert as usual but only log the changes that would be made to endpoints in Kubernetes"),
+        )
+        .arg(
+            Arg::with_name("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_name("SECONDS")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_SHUTDOWN_TIMEOUT)
+                .help("Time given to restore removed endpoints on SIGTERM or SIGINT before exiting anyway"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .required(false)
+                .takes_value(true)
+                .help(
+                    "YAML or TOML(*.toml) file of allow and block lists, defaults and \
+                    per namespace/service overrides of probe, ports, remove, restore, \
+                    timeout, interval and alert, reloaded on SIGHUP or once modified",
                 ),
         )
-        .get_matches();
+        .subcommand(admin_subcommand(
+            "drain",
+            "Remove an endpoint and keep it out until released or the ttl expires",
+        ))
+        .subcommand(admin_subcommand(
+            "restore",
+            "Restore an endpoint and keep it in until released or the ttl expires",
+        ))
+        .subcommand(admin_subcommand(
+            "release",
+            "Hand an endpoint drained or restored back to probes",
+        ))
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manage the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Check a config file and exit, without starting")
+                        .arg(
+                            Arg::with_name("file")
+                                .value_name("FILE")
+                                .required(true)
+                                .help("The config file to check"),
+                        ),
+                ),
+        )
+}
+
+// problems with options, collected to be reported all at once
+#[derive(Debug, Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    // value of the option name parsed, or default if it's not given or
+    //  invalid, flag is how it's given on the command line
+    fn parse<T>(&mut self, matches: &ArgMatches, name: &str, flag: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match matches.value_of(name) {
+            Some(value) => value.parse().unwrap_or_else(|e| {
+                self.0.push(format!("invalid {} {:?}: {}", flag, value, e));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    fn require(&mut self, ok: bool, problem: &str) {
+        if !ok {
+            self.0.push(problem.to_owned());
+        }
+    }
+}
+
+fn from_matches(matches: &ArgMatches) -> Result<AppOpt, Vec<String>> {
+    let mut problems = Problems::default();
+
+    let namespaces: Option<Vec<String>> = matches
+        .values_of("namespace")
+        .map(|values| values.map(|el| el.to_owned()).collect());
+    let all_namespaces = matches.is_present("all_namespaces");
 
     let allow_list_values = matches.values_of("allow_list");
-    let allow_list: Option<Vec<String>> = match allow_list_values {
-        Some(values) => Some(values.map(|el| el.to_owned()).collect()),
-        None => None,
-    };
+    let allow_list: Option<Vec<String>> =
+        allow_list_values.map(|values| values.map(|el| el.to_owned()).collect());
 
     let block_list_values = matches.values_of("block_list");
-    let block_list: Option<Vec<String>> = match block_list_values {
-        Some(values) => Some(values.map(|el| el.to_owned()).collect()),
-        None => None,
-    };
+    let block_list: Option<Vec<String>> =
+        block_list_values.map(|values| values.map(|el| el.to_owned()).collect());
 
-    let refresh_interval: u64 = match matches.value_of("refresh_interval") {
-        Some(i) => i.parse().unwrap(),
-        None => DEFAULT_REFRESH_INTERVAL.parse().unwrap(),
+    let backend = match matches.value_of("backend") {
+        Some(b) => Backend::from_str(b).unwrap(),
+        None => Backend::from_str(DEFAULT_BACKEND).unwrap(),
     };
 
-    let probe_interval: u64 = match matches.value_of("probe_interval") {
-        Some(i) => i.parse().unwrap(),
-        None => DEFAULT_PROBE_INTERVAL.parse().unwrap(),
-    };
+    let refresh_interval: u64 = problems.parse(
+        matches,
+        "refresh_interval",
+        "--refresh_interval",
+        DEFAULT_REFRESH_INTERVAL.parse().unwrap(),
+    );
 
-    let connection_timeout: u64 = match matches.value_of("connection_timeout") {
-        Some(i) => i.parse().unwrap(),
-        None => DEFAULT_CONNECT_TIMEOUT.parse().unwrap(),
-    };
+    let watch = matches.is_present("watch");
 
-    let restore: u32 = match matches.value_of("restore") {
-        Some(i) => i.parse().unwrap(),
-        None => DEFAULT_RESTORE.parse().unwrap(),
-    };
+    let probe_interval: u64 = problems.parse(
+        matches,
+        "probe_interval",
+        "--probe_interval",
+        DEFAULT_PROBE_INTERVAL.parse().unwrap(),
+    );
 
-    let remove: u32 = match matches.value_of("remove") {
-        Some(i) => i.parse().unwrap(),
-        None => DEFAULT_REMOVE.parse().unwrap(),
-    };
+    let removed_probe_interval: u64 = problems.parse(
+        matches,
+        "removed_probe_interval",
+        "--removed-probe-interval",
+        probe_interval,
+    );
 
-    let cluster_name: Option<String> = match matches.value_of("cluster_name") {
-        Some(i) => Some(i.to_owned()),
-        None => None,
-    };
+    let max_removed_probe_interval: u64 = problems.parse(
+        matches,
+        "max_removed_probe_interval",
+        "--max-removed-probe-interval",
+        removed_probe_interval,
+    );
 
-    let alert_channel: Option<String> = match matches.value_of("alert") {
-        Some(i) => Some(i.to_owned()),
-        None => None,
-    };
+    let probe_jitter: u32 = problems.parse(
+        matches,
+        "probe_jitter",
+        "--probe-jitter",
+        DEFAULT_PROBE_JITTER.parse().unwrap(),
+    );
+
+    let connection_timeout: u64 = problems.parse(
+        matches,
+        "connection_timeout",
+        "--connection_timeout",
+        DEFAULT_CONNECT_TIMEOUT.parse().unwrap(),
+    );
+
+    let max_probes: usize = problems.parse(
+        matches,
+        "max_probes",
+        "--max-probes",
+        DEFAULT_MAX_PROBES.parse().unwrap(),
+    );
+
+    let max_service_probes: usize = problems.parse(
+        matches,
+        "max_service_probes",
+        "--max-service-probes",
+        DEFAULT_MAX_SERVICE_PROBES.parse().unwrap(),
+    );
+
+    let mut rules = vec![];
+    for rule in matches.values_of("probe").into_iter().flatten() {
+        match ProbeRule::from_str(rule) {
+            Ok(rule) => rules.push(rule),
+            Err(e) => problems
+                .0
+                .push(format!("invalid --probe {:?}: {}", rule, e.reason())),
+        }
+    }
+    let probes = Probes::new(rules);
+
+    let restore: u32 = problems.parse(
+        matches,
+        "restore",
+        "--restore",
+        DEFAULT_RESTORE.parse().unwrap(),
+    );
+
+    let remove: u32 = problems.parse(
+        matches,
+        "remove",
+        "--remove",
+        DEFAULT_REMOVE.parse().unwrap(),
+    );
+
+    let cluster_name: Option<String> = matches.value_of("cluster_name").map(|i| i.to_owned());
+
+    let alert_channels: Vec<String> = matches
+        .values_of("alert")
+        .map(|values| values.map(|el| el.to_owned()).collect())
+        .unwrap_or_default();
+    if let Err(e) = Alert::from_urls(&alert_channels) {
+        problems.0.push(format!("invalid --alert: {}", e.reason()));
+    }
 
-    AppOpt {
+    let dry_run = matches.is_present("dry_run");
+
+    let shutdown_timeout: u64 = problems.parse(
+        matches,
+        "shutdown_timeout",
+        "--shutdown-timeout",
+        DEFAULT_SHUTDOWN_TIMEOUT.parse().unwrap(),
+    );
+
+    let config: Option<String> = matches.value_of("config").map(|i| i.to_owned());
+
+    let listen: SocketAddr = problems.parse(
+        matches,
+        "listen",
+        "--listen",
+        DEFAULT_LISTEN.parse().unwrap(),
+    );
+    let admin_listen: SocketAddr = problems.parse(
+        matches,
+        "admin-listen",
+        "--admin-listen",
+        DEFAULT_ADMIN_LISTEN.parse().unwrap(),
+    );
+    problems.require(
+        admin_listen != listen,
+        "--admin-listen must not be the same as --listen",
+    );
+
+    problems.require(refresh_interval > 0, "--refresh_interval must be positive");
+    problems.require(probe_interval > 0, "--probe_interval must be positive");
+    problems.require(
+        removed_probe_interval > 0,
+        "--removed-probe-interval must be positive",
+    );
+    problems.require(
+        max_removed_probe_interval >= removed_probe_interval,
+        "--max-removed-probe-interval must not be less than --removed-probe-interval",
+    );
+    problems.require(probe_jitter <= 100, "--probe-jitter must be at most 100");
+    problems.require(
+        connection_timeout > 0,
+        "--connection_timeout must be positive",
+    );
+    problems.require(max_probes > 0, "--max-probes must be positive");
+    problems.require(
+        max_service_probes > 0,
+        "--max-service-probes must be positive",
+    );
+    problems.require(remove > 0, "--remove must be positive");
+    problems.require(restore > 0, "--restore must be positive");
+    if !problems.0.is_empty() {
+        return Err(problems.0);
+    }
+
+    Ok(AppOpt {
+        namespaces,
+        all_namespaces,
         allow_list,
         block_list,
+        backend,
         refresh_interval,
+        watch,
         probe_interval,
+        removed_probe_interval,
+        max_removed_probe_interval,
+        probe_jitter,
         connection_timeout,
+        probes,
+        max_probes,
+        max_service_probes,
         restore,
         remove,
         cluster_name,
-        alert_channel,
+        alert_channels,
+        listen,
+        admin_listen,
+        dry_run,
+        shutdown_timeout,
+        config,
+        admin: admin_command(matches),
+        check_config: matches
+            .subcommand_matches("config")
+            .and_then(|matches| matches.subcommand_matches("check"))
+            .and_then(|matches| matches.value_of("file"))
+            .map(|file| file.to_owned()),
+    })
+}
+
+fn admin_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("service")
+                .value_name("NAMESPACE/NAME")
+                .required(true)
+                .validator(|s| match s.split_once('/') {
+                    Some((ns, name)) if !ns.is_empty() && !name.is_empty() => Ok(()),
+                    _ => Err(format!("invalid service {}", s)),
+                })
+                .help("The service the endpoint belongs to"),
+        )
+        .arg(
+            Arg::with_name("endpoint")
+                .value_name("ENDPOINT")
+                .required(true)
+                .help("IP:PORT of the endpoint, or IP for all ports of the address"),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("SECONDS")
+                .required(false)
+                .takes_value(true)
+                .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help(
+                    "Release the endpoint after this many seconds, \
+                    restore defaults to 600",
+                ),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .value_name("URL")
+                .required(false)
+                .takes_value(true)
+                .default_value(DEFAULT_SERVER)
+                .help("URL of the admin API of the running ephc to send the request to"),
+        )
+}
+
+fn admin_command(matches: &ArgMatches) -> Option<admin::Command> {
+    let (action, matches) = match matches.subcommand() {
+        (name, Some(matches)) => (Action::from_str(name).ok()?, matches),
+        _ => return None,
+    };
+    let (namespace, name) = matches
+        .value_of("service")
+        .unwrap()
+        .split_once('/')
+        .unwrap();
+    Some(admin::Command {
+        action,
+        server: matches
+            .value_of("server")
+            .unwrap_or(DEFAULT_SERVER)
+            .to_owned(),
+        namespace: namespace.to_owned(),
+        name: name.to_owned(),
+        endpoint: matches.value_of("endpoint").unwrap().to_owned(),
+        ttl: matches.value_of("ttl").map(|t| t.parse().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt(args: &[&str]) -> Result<AppOpt, Vec<String>> {
+        let matches = app()
+            .get_matches_from_safe(std::iter::once("ephc").chain(args.iter().copied()))
+            .unwrap();
+        from_matches(&matches)
+    }
+
+    #[test]
+    fn validate() {
+        let opt = opt(&["-p", "500"]).unwrap();
+        assert_eq!(opt.probe_interval, 500);
+        assert_eq!(opt.removed_probe_interval, 500);
+        assert!(opt.admin.is_none() && opt.check_config.is_none());
+        assert!(opt.admin_listen.ip().is_loopback());
+        assert!(super::tests::opt(&["--admin-listen", "0.0.0.0:8080"]).is_err());
+
+        // all of them at once
+        let problems = super::tests::opt(&[
+            "-p",
+            "1s",
+            "-r",
+            "0",
+            "--probe-jitter",
+            "101",
+            "-P",
+            "api=ftp",
+            "-A",
+            "teams://https://example.com",
+        ])
+        .unwrap_err();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].starts_with("invalid --probe_interval \"1s\""));
+    }
+
+    #[test]
+    fn config_check() {
+        let opt = opt(&["config", "check", "ephc.yaml"]).unwrap();
+        assert_eq!(opt.check_config.as_deref(), Some("ephc.yaml"));
+        assert!(opt.admin.is_none());
     }
 }
@@ -0,0 +1,468 @@
+use glob::Pattern;
+use log::{error, info};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use std::collections::{hash_map::Entry, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::alert::Alert;
+use crate::error::{Error, Result};
+use crate::kube::{self, Endpoint, Selector, Services, Threshold};
+use crate::probe::{Probe, Schedule};
+
+// how often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// what applies to a service, the command line options overridden by the
+//  defaults of the config file then by every override matching the service
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServiceConfig {
+    pub threshold: Threshold,
+    pub schedule: Schedule,
+    // of a probe, in milliseconds
+    pub timeout: u64,
+    // None for the probe picked by the rules of -P or by the port
+    pub probe: Option<Probe>,
+    // names or numbers of the ports to probe, None for every port
+    pub ports: Option<Vec<String>>,
+    pub alerter: Arc<Alert>,
+}
+
+impl ServiceConfig {
+    // whether ep is on a port to be probed
+    pub fn selects(&self, ep: &Endpoint) -> bool {
+        match &self.ports {
+            None => true,
+            Some(ports) => ports.iter().any(|port| {
+                ep.port_name.as_ref() == Some(port) || *port == ep.addr.port().to_string()
+            }),
+        }
+    }
+}
+
+// settings of the config file, unset ones are left as they are and unknown
+//  ones are refused, so that a typo isn't silently ignored
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    // TYPE[,KEY[=VALUE]...] as with -P
+    #[serde(default, deserialize_with = "probe")]
+    probe: Option<Probe>,
+    #[serde(default, deserialize_with = "ports")]
+    ports: Option<Vec<String>>,
+    remove: Option<u32>,
+    restore: Option<u32>,
+    // milliseconds
+    timeout: Option<u64>,
+    interval: Option<u64>,
+    // scheme://url as with --alert, or a list of them
+    #[serde(default, deserialize_with = "urls")]
+    alert: Option<Vec<String>>,
+}
+
+impl Settings {
+    // what's wrong with them, each problem prefixed with where
+    fn validate(&self, at: &str, problems: &mut Vec<String>) {
+        let positive = [
+            ("remove", self.remove.map(u64::from)),
+            ("restore", self.restore.map(u64::from)),
+            ("timeout", self.timeout),
+            ("interval", self.interval),
+        ];
+        for (name, value) in positive.iter() {
+            if *value == Some(0) {
+                problems.push(format!("{}: {} must be positive", at, name));
+            }
+        }
+        if let Err(e) = Alert::from_urls(self.alert.as_deref().unwrap_or_default()) {
+            problems.push(format!("{}: {}", at, e.reason()));
+        }
+    }
+
+    fn apply(&self, cfg: &mut ServiceConfig, alerters: &HashMap<Vec<String>, Arc<Alert>>) {
+        if let Some(probe) = &self.probe {
+            cfg.probe = Some(probe.clone());
+        }
+        if let Some(ports) = &self.ports {
+            cfg.ports = Some(ports.clone());
+        }
+        if let Some(remove) = self.remove {
+            cfg.threshold.remove = remove;
+        }
+        if let Some(restore) = self.restore {
+            cfg.threshold.restore = restore;
+        }
+        if let Some(timeout) = self.timeout {
+            cfg.timeout = timeout;
+        }
+        if let Some(interval) = self.interval {
+            cfg.schedule.interval = Duration::from_millis(interval);
+        }
+        if let Some(alerter) = self.alert.as_ref().and_then(|urls| alerters.get(urls)) {
+            cfg.alerter = alerter.clone();
+        }
+    }
+}
+
+// settings for the services matching both globs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Override {
+    #[serde(default = "any", deserialize_with = "pattern")]
+    namespace: Pattern,
+    #[serde(default = "any", deserialize_with = "pattern")]
+    service: Pattern,
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    // in place of --allow and --block
+    allow: Option<Vec<String>>,
+    block: Option<Vec<String>>,
+    #[serde(default)]
+    defaults: Settings,
+    // applied in order, so a later one overrides what an earlier one sets
+    #[serde(default)]
+    overrides: Vec<Override>,
+}
+
+// Configuration of every service, from the command line and optionally a
+//  YAML or TOML config file
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    base: ServiceConfig,
+    file: File,
+    // of every alert setting in the file
+    alerters: HashMap<Vec<String>, Arc<Alert>>,
+}
+
+impl Config {
+    // base is what the command line gives
+    pub fn new(base: ServiceConfig) -> Self {
+        Self {
+            base,
+            ..Default::default()
+        }
+    }
+
+    // Read the config file at path, TOML if it's named *.toml otherwise
+    //  YAML. Every problem found is in the error, one per line.
+    pub fn load(base: ServiceConfig, path: &Path) -> Result<Self> {
+        let invalid =
+            |e: &dyn std::fmt::Display| Error::config(format!("invalid {}: {}", path.display(), e));
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::config(format!("failed to read {}: {}", path.display(), e)))?;
+        let file = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| invalid(&e))?,
+            _ => serde_yaml::from_str(&content).map_err(|e| invalid(&e))?,
+        };
+        Self::from_file(base, file).map_err(|e| invalid(&e.reason()))
+    }
+
+    // the file at path read again, over the same command line
+    pub fn reload(&self, path: &Path) -> Result<Self> {
+        Self::load(self.base.clone(), path)
+    }
+
+    fn from_file(base: ServiceConfig, file: File) -> Result<Self> {
+        let mut problems = vec![];
+        file.defaults.validate("defaults", &mut problems);
+        for (i, o) in file.overrides.iter().enumerate() {
+            o.settings
+                .validate(&format!("overrides[{}]", i), &mut problems);
+        }
+        if !problems.is_empty() {
+            return Err(Error::config(format!("\n  {}", problems.join("\n  "))));
+        }
+
+        let mut alerters = HashMap::new();
+        let alerts = std::iter::once(&file.defaults)
+            .chain(file.overrides.iter().map(|o| &o.settings))
+            .filter_map(|settings| settings.alert.clone());
+        for urls in alerts {
+            if let Entry::Vacant(entry) = alerters.entry(urls) {
+                let alerter = Alert::from_urls(entry.key())?;
+                entry.insert(Arc::new(alerter));
+            }
+        }
+        Ok(Self {
+            base,
+            file,
+            alerters,
+        })
+    }
+
+    pub fn service(&self, namespace: &str, name: &str) -> ServiceConfig {
+        let mut cfg = self.base.clone();
+        self.file.defaults.apply(&mut cfg, &self.alerters);
+        for o in &self.file.overrides {
+            if o.namespace.matches(namespace) && o.service.matches(name) {
+                o.settings.apply(&mut cfg, &self.alerters);
+            }
+        }
+        cfg
+    }
+
+    // base with the allow and block lists of the file, if it's got them
+    pub fn selector(&self, base: &Selector) -> Selector {
+        Selector {
+            allow: self.file.allow.clone().or_else(|| base.allow.clone()),
+            block: self.file.block.clone().or_else(|| base.block.clone()),
+            ..base.clone()
+        }
+    }
+}
+
+// Reloads the config file at path on SIGHUP or once it's modified, updates
+//  the services in svcs and hands the new config to every receiver of tx.
+// An invalid file is logged and the config in effect kept.
+pub(crate) async fn reload(
+    path: Option<PathBuf>,
+    tx: watch::Sender<Arc<Config>>,
+    svcs: Services,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interval = time::interval(POLL_INTERVAL);
+    let mut last_modified = path.as_deref().and_then(modified);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!("got SIGHUP, reloading config"),
+            _ = interval.tick(), if path.is_some() => {
+                let modified = path.as_deref().and_then(modified);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("config file changed, reloading config");
+            }
+        }
+        let path = match &path {
+            Some(path) => path,
+            None => {
+                info!("no config file to reload");
+                continue;
+            }
+        };
+        let current = tx.borrow().clone();
+        let config = match current.reload(path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("failed to reload config, keeping the one in effect: {}", e);
+                continue;
+            }
+        };
+        kube::reconfigure(&svcs, &config).await;
+        tx.send_replace(Arc::new(config));
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn any() -> Pattern {
+    Pattern::new("*").unwrap()
+}
+
+fn pattern<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Pattern, D::Error> {
+    let s = String::deserialize(d)?;
+    Pattern::new(&s).map_err(|e| D::Error::custom(format!("invalid glob {}: {}", s, e)))
+}
+
+fn probe<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Probe>, D::Error> {
+    let s = String::deserialize(d)?;
+    Probe::from_str(&s)
+        .map(Some)
+        .map_err(|e| D::Error::custom(e.reason()))
+}
+
+// one url or a list of them
+fn urls<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Urls {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(Some(match Urls::deserialize(d)? {
+        Urls::One(url) => vec![url],
+        Urls::Many(urls) => urls,
+    }))
+}
+
+// ports are names or numbers
+fn ports<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        Name(String),
+    }
+    let ports = Vec::<Port>::deserialize(d)?;
+    Ok(Some(
+        ports
+            .into_iter()
+            .map(|port| match port {
+                Port::Number(n) => n.to_string(),
+                Port::Name(name) => name,
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> ServiceConfig {
+        ServiceConfig {
+            threshold: Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            timeout: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn yaml() {
+        let file: File = serde_yaml::from_str(
+            "
+            defaults:
+              remove: 5
+              timeout: 200
+            overrides:
+            - namespace: prod-*
+              probe: http,path=/healthz
+              ports: [http, 8080]
+            - namespace: prod-eu
+              service: api
+              remove: 1
+              alert: wecom://https://example.com
+            - service: batch-*
+              interval: 5000
+            ",
+        )
+        .unwrap();
+        let config = Config::from_file(base(), file).unwrap();
+
+        let cfg = config.service("dev", "api");
+        assert_eq!(cfg.threshold.remove, 5);
+        assert_eq!(cfg.threshold.restore, 3);
+        assert_eq!(cfg.timeout, 200);
+        assert!(cfg.probe.is_none() && cfg.ports.is_none());
+
+        let cfg = config.service("prod-us", "api");
+        assert!(matches!(cfg.probe, Some(Probe::Http(_))));
+        assert_eq!(cfg.ports, Some(vec!["http".to_owned(), "8080".to_owned()]));
+        assert_eq!(cfg.threshold.remove, 5);
+
+        let cfg = config.service("prod-eu", "api");
+        assert!(matches!(cfg.probe, Some(Probe::Http(_))));
+        assert_eq!(cfg.threshold.remove, 1);
+        assert!(Arc::ptr_eq(
+            &cfg.alerter,
+            &config.alerters[&vec!["wecom://https://example.com".to_owned()]]
+        ));
+
+        let cfg = config.service("dev", "batch-daily");
+        assert_eq!(cfg.schedule.interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn toml() {
+        let file: File = toml::from_str(
+            r#"
+            [defaults]
+            restore = 2
+            alert = ["wecom://https://example.com/1", "wecom://https://example.com/2"]
+
+            [[overrides]]
+            namespace = "kube-*"
+            ports = [53]
+            probe = "dns,name=kubernetes.default.svc.cluster.local"
+            "#,
+        )
+        .unwrap();
+        let config = Config::from_file(base(), file).unwrap();
+        assert_eq!(config.service("default", "api").threshold.restore, 2);
+        let cfg = config.service("kube-system", "kube-dns");
+        assert!(matches!(cfg.probe, Some(Probe::Dns(_))));
+        assert_eq!(cfg.ports, Some(vec!["53".to_owned()]));
+        assert_eq!(
+            format!("{:?}", cfg.alerter),
+            r#"Alert { channels: ["wecom", "wecom"] }"#
+        );
+    }
+
+    #[test]
+    fn reload() {
+        let path = std::env::temp_dir().join(format!("ephc-{}.yaml", std::process::id()));
+        std::fs::write(&path, "block: [prod/*]\ndefaults: {restore: 1}").unwrap();
+        let config = Config::load(base(), &path).unwrap();
+        assert_eq!(config.service("default", "api").threshold.restore, 1);
+
+        std::fs::write(&path, "defaults: {restore: 2}").unwrap();
+        let config = config.reload(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let cfg = config.service("default", "api");
+        assert_eq!((cfg.threshold.restore, cfg.timeout), (2, 100));
+
+        let selector = Selector {
+            namespaces: kube::Namespaces::All,
+            allow: None,
+            block: Some(vec!["kubernetes".to_owned()]),
+            backend: kube::Backend::Endpoints,
+        };
+        assert_eq!(config.selector(&selector).block, selector.block);
+        let file: File = serde_yaml::from_str("allow: [api]").unwrap();
+        let reloaded = Config::from_file(base(), file).unwrap().selector(&selector);
+        assert_eq!(reloaded.allow, Some(vec!["api".to_owned()]));
+        assert_eq!(reloaded.block, selector.block);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(serde_yaml::from_str::<File>("defaults: {probe: bogus}").is_err());
+        assert!(serde_yaml::from_str::<File>("overrides: [{service: '[a'}]").is_err());
+
+        let file: File = serde_yaml::from_str(
+            "
+            defaults: {remove: 0, alert: https://example.com}
+            overrides:
+            - {service: api, interval: 0}
+            ",
+        )
+        .unwrap();
+        let e = Config::from_file(base(), file).unwrap_err().reason();
+        assert_eq!(e.lines().count(), 4, "{}", e);
+        assert!(e.contains("overrides[0]: interval must be positive"));
+    }
+
+    #[test]
+    fn typo() {
+        // as with config check
+        let check = |name: &str, content: &str| {
+            let path = std::env::temp_dir().join(format!("ephc-{}-{}", std::process::id(), name));
+            std::fs::write(&path, content).unwrap();
+            let res = Config::load(base(), &path);
+            std::fs::remove_file(&path).unwrap();
+            res.unwrap_err().reason()
+        };
+        let e = check("typo.yaml", "defaults: {remove_threshold: 1}");
+        assert!(e.contains("unknown field `remove_threshold`"), "{}", e);
+        let e = check("typo.yaml", "overrides: [{service: api, intreval: 5000}]");
+        assert!(e.contains("unknown field `intreval`"), "{}", e);
+        let e = check("typo.toml", "blocks = [\"prod/*\"]");
+        assert!(e.contains("unknown field `blocks`"), "{}", e);
+    }
+}
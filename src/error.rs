@@ -15,29 +15,77 @@ impl fmt::Display for OtherError {
     }
 }
 
+// error from the Kubernetes API or its configuration
+#[derive(Debug)]
+struct KubeError {
+    // HTTP status code if the API server responded
+    code: Option<u16>,
+    message: String,
+}
+
+impl std::error::Error for KubeError {}
+
+impl fmt::Display for KubeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "status {}: {}", code, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// error with a message built at runtime
+#[derive(Debug)]
+struct MessageError {
+    message: String,
+}
+
+impl std::error::Error for MessageError {}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum ErrorKind {
     Io,
     Serde,
     AddrParseError,
+    Http,
+    Kube,
+    Probe,
+    Timeout,
+    Config,
+    NotFound,
+    Alert,
     Other,
 }
 
-impl std::string::ToString for ErrorKind {
-    fn to_string(&self) -> String {
-        match self {
-            ErrorKind::Io => String::from("io"),
-            ErrorKind::Serde => String::from("serde"),
-            ErrorKind::AddrParseError => String::from("AddrParseError"),
-            ErrorKind::Other => String::from("other"),
-        }
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ErrorKind::Io => "io",
+            ErrorKind::Serde => "serde",
+            ErrorKind::AddrParseError => "AddrParseError",
+            ErrorKind::Http => "http",
+            ErrorKind::Kube => "kube",
+            ErrorKind::Probe => "probe",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Config => "config",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::Alert => "alert",
+            ErrorKind::Other => "other",
+        };
+        write!(f, "{}", s)
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct Error {
     kind: ErrorKind,
-    inner: Box<dyn std::error::Error>,
+    inner: Box<dyn std::error::Error + Send + Sync>,
 }
 
 impl Error {
@@ -47,16 +95,101 @@ impl Error {
             inner: Box::new(OtherError { reason }),
         }
     }
+
+    pub fn kube<S: Into<String>>(code: Option<u16>, message: S) -> Self {
+        Self {
+            kind: ErrorKind::Kube,
+            inner: Box::new(KubeError {
+                code,
+                message: message.into(),
+            }),
+        }
+    }
+
+    // a probe reached the endpoint but didn't like what it got
+    pub fn probe<S: Into<String>>(message: S) -> Self {
+        Self {
+            kind: ErrorKind::Probe,
+            inner: Box::new(MessageError {
+                message: message.into(),
+            }),
+        }
+    }
+
+    pub fn timeout(reason: &'static str) -> Self {
+        Self {
+            kind: ErrorKind::Timeout,
+            inner: Box::new(OtherError { reason }),
+        }
+    }
+
+    // invalid options or configuration
+    pub fn config<S: Into<String>>(message: S) -> Self {
+        Self {
+            kind: ErrorKind::Config,
+            inner: Box::new(MessageError {
+                message: message.into(),
+            }),
+        }
+    }
+
+    // no such service or endpoint is being checked
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Self {
+            kind: ErrorKind::NotFound,
+            inner: Box::new(MessageError {
+                message: message.into(),
+            }),
+        }
+    }
+
+    // an alert channel took the message but refused it
+    pub fn alert<S: Into<String>>(message: S) -> Self {
+        Self {
+            kind: ErrorKind::Alert,
+            inner: Box::new(MessageError {
+                message: message.into(),
+            }),
+        }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    // what went wrong without the kind, for messages meant for users
+    pub fn reason(&self) -> String {
+        self.inner.to_string()
+    }
+
+    // the first error of type T this error is caused by, if any
+    pub fn source_of<T: std::error::Error + 'static>(&self) -> Option<&T> {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(self.inner.as_ref());
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<T>() {
+                return Some(e);
+            }
+            source = e.source();
+        }
+        None
+    }
+
+    // kind of the io error this error is caused by, if any
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
+        self.source_of::<std::io::Error>().map(|e| e.kind())
+    }
+
+    // HTTP status code of a failed Kubernetes API call
+    pub fn kube_code(&self) -> Option<u16> {
+        self.inner.downcast_ref::<KubeError>().and_then(|e| e.code)
+    }
 }
 
+impl std::error::Error for Error {}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "ephc error, kind: {}, err: {}",
-            self.kind.to_string(),
-            self.inner.to_string()
-        )
+        write!(f, "ephc error, kind: {}, err: {}", self.kind, self.inner)
     }
 }
 
@@ -69,6 +202,15 @@ impl From<serde_yaml::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self {
+            kind: ErrorKind::Serde,
+            inner: Box::new(e),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
         Self {
@@ -86,3 +228,12 @@ impl From<std::net::AddrParseError> for Error {
         }
     }
 }
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self {
+            kind: ErrorKind::Http,
+            inner: Box::new(e),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use super::Threshold;
+use crate::probe::Probe;
+
+// Service owners tune checking of their service with these annotations on
+//  it, or its Endpoints or EndpointSlices, which override those of the
+//  Service, without touching the deployment of ephc
+const PREFIX: &str = "ephc.io/";
+const ENABLED: &str = "ephc.io/enabled";
+const PROBE: &str = "ephc.io/probe";
+const PATH: &str = "ephc.io/path";
+const REMOVE_THRESHOLD: &str = "ephc.io/remove-threshold";
+const RESTORE_THRESHOLD: &str = "ephc.io/restore-threshold";
+const TIMEOUT: &str = "ephc.io/timeout-ms";
+
+// settings of a service from its annotations, an invalid one is left unset
+#[derive(Debug, Clone)]
+pub(crate) struct Annotations {
+    // false to leave the service alone
+    pub enabled: bool,
+    pub probe: Option<Probe>,
+    pub remove: Option<u32>,
+    pub restore: Option<u32>,
+    // milliseconds
+    pub timeout: Option<u64>,
+    // why the invalid ones are ignored, reported once the service is picked up
+    pub errors: Vec<String>,
+    // the ones parsed, to tell when they change
+    pub source: BTreeMap<String, String>,
+}
+
+impl Default for Annotations {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            probe: None,
+            remove: None,
+            restore: None,
+            timeout: None,
+            errors: vec![],
+            source: BTreeMap::new(),
+        }
+    }
+}
+
+impl Annotations {
+    pub fn parse(annotations: &BTreeMap<String, String>) -> Self {
+        let mut parsed = Self::default();
+        for (key, value) in annotations.range(PREFIX.to_owned()..) {
+            if !key.starts_with(PREFIX) {
+                break;
+            }
+            let res = match key.as_str() {
+                ENABLED => parse(value).map(|v| parsed.enabled = v),
+                REMOVE_THRESHOLD => positive(value).map(|v| parsed.remove = Some(v)),
+                RESTORE_THRESHOLD => positive(value).map(|v| parsed.restore = Some(v)),
+                TIMEOUT => positive(value).map(|v| parsed.timeout = Some(v)),
+                // parsed along with the probe
+                PROBE | PATH => Ok(()),
+                // ours, not the owner's
+                super::state::REMOVED_ANNOTATION => continue,
+                _ => Err("unknown annotation".to_owned()),
+            };
+            parsed.source.insert(key.clone(), value.clone());
+            if let Err(e) = res {
+                parsed.errors.push(format!("{}={:?}: {}", key, value, e));
+            }
+        }
+
+        // the path is an option of an http probe, the one to use if no probe
+        //  is given
+        let probe = match (annotations.get(PROBE), annotations.get(PATH)) {
+            (Some(probe), Some(path)) => Some(format!("{},path={}", probe, path)),
+            (None, Some(path)) => Some(format!("http,path={}", path)),
+            (probe, None) => probe.cloned(),
+        };
+        if let Some(probe) = probe {
+            match Probe::from_str(&probe) {
+                Ok(probe) => parsed.probe = Some(probe),
+                Err(e) => parsed
+                    .errors
+                    .push(format!("{} {:?}: {}", PROBE, probe, e.reason())),
+            }
+        }
+        parsed
+    }
+
+    // merge the annotations of several objects of a service, those of later
+    //  ones override those of earlier ones
+    pub fn merge<'a>(annotations: impl Iterator<Item = &'a BTreeMap<String, String>>) -> Self {
+        let mut merged = BTreeMap::new();
+        for a in annotations {
+            merged.extend(a.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        Self::parse(&merged)
+    }
+
+    // threshold overridden by the annotations
+    pub fn threshold(&self, mut threshold: Threshold) -> Threshold {
+        if let Some(remove) = self.remove {
+            threshold.remove = remove;
+        }
+        if let Some(restore) = self.restore {
+            threshold.restore = restore;
+        }
+        threshold
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| e.to_string())
+}
+
+// thresholds and timeouts of 0 make no sense, as in the config file
+fn positive<T: FromStr + Default + PartialEq>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let v = parse(value)?;
+    if v == T::default() {
+        return Err("must be positive".to_owned());
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = Annotations::parse(&annotations(&[
+            ("app.kubernetes.io/name", "api"),
+            ("ephc.io/path", "/healthz"),
+            ("ephc.io/remove-threshold", "5"),
+            ("ephc.io/timeout-ms", "250"),
+            ("ephc.io/removed", "[]"),
+        ]));
+        assert!(parsed.enabled);
+        assert!(matches!(&parsed.probe, Some(Probe::Http(probe)) if probe.path == "/healthz"));
+        let threshold = parsed.threshold(Threshold {
+            restore: 3,
+            remove: 3,
+        });
+        assert_eq!((threshold.restore, threshold.remove), (3, 5));
+        assert_eq!(parsed.timeout, Some(250));
+        assert!(parsed.errors.is_empty());
+        assert!(!parsed.source.contains_key("ephc.io/removed"));
+        assert_eq!(parsed.source.len(), 3);
+
+        let parsed = Annotations::parse(&annotations(&[
+            ("ephc.io/enabled", "no"),
+            ("ephc.io/probe", "tcp"),
+            ("ephc.io/path", "/healthz"),
+            ("ephc.io/restore-threshold", "-1"),
+            ("ephc.io/timeout", "250"),
+        ]));
+        // invalid ones are left unset
+        assert!(parsed.enabled);
+        assert!(parsed.probe.is_none() && parsed.restore.is_none());
+        assert_eq!(parsed.errors.len(), 4);
+
+        let parsed = Annotations::parse(&annotations(&[
+            ("ephc.io/enabled", "false"),
+            ("ephc.io/probe", "grpc"),
+        ]));
+        assert!(!parsed.enabled);
+        assert!(matches!(parsed.probe, Some(Probe::Grpc(_))));
+
+        let parsed = Annotations::parse(&annotations(&[
+            ("ephc.io/remove-threshold", "0"),
+            ("ephc.io/restore-threshold", "0"),
+            ("ephc.io/timeout-ms", "0"),
+        ]));
+        assert!(parsed.remove.is_none() && parsed.restore.is_none() && parsed.timeout.is_none());
+        assert_eq!(parsed.errors.len(), 3);
+        assert!(parsed.errors[0].ends_with("must be positive"));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::{Error, Result};
+use log::debug;
+use reqwest::{header, Method, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+use super::config::Config;
+
+const YAML: &str = "application/yaml";
+const JSON: &str = "application/json";
+const MERGE_PATCH: &str = "application/merge-patch+json";
+// let the server end watches every now and then so a silently dead
+//  connection doesn't hang forever
+const WATCH_TIMEOUT_SECS: &str = "290";
+// Callers hold the lock of a service across a request, which an API server
+//  that never answers isn't to hold forever. Watches are only given the
+//  connect timeout, their responses go on for minutes.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A minimal client for the Kubernetes API, covering only what ephc needs
+pub(crate) struct Client {
+    http: reqwest::Client,
+    server: String,
+    token: Option<String>,
+    basic_auth: Option<(String, Option<String>)>,
+    // namespace of the current context or the service account
+    pub namespace: String,
+    // never write to k8s, changes are only logged
+    pub dry_run: bool,
+}
+
+impl Client {
+    pub fn new(cfg: Config) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .connect_timeout(REQUEST_TIMEOUT)
+            .danger_accept_invalid_certs(cfg.insecure);
+        if let Some(ca) = &cfg.ca_cert {
+            for cert in reqwest::Certificate::from_pem_bundle(ca)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some(identity) = &cfg.identity {
+            builder = builder.identity(reqwest::Identity::from_pem(identity)?);
+        }
+        let password = cfg.password;
+        Ok(Self {
+            http: builder.build()?,
+            server: cfg.server,
+            token: cfg.token,
+            basic_auth: cfg.username.map(|u| (u, password)),
+            namespace: cfg.namespace,
+            dry_run: false,
+        })
+    }
+
+    pub fn infer() -> Result<Self> {
+        Self::new(Config::infer()?)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = self
+            .http
+            .request(method, format!("{}{}", self.server, path));
+        if let Some(token) = &self.token {
+            return req.bearer_auth(token);
+        }
+        if let Some((username, password)) = &self.basic_auth {
+            return req.basic_auth(username, password.as_ref());
+        }
+        req
+    }
+
+    async fn send(&self, req: RequestBuilder) -> Result<String> {
+        let resp = req.timeout(REQUEST_TIMEOUT).send().await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if !status.is_success() {
+            return Err(Error::kube(Some(status.as_u16()), body));
+        }
+        Ok(body)
+    }
+
+    // get a single object as yaml, the same format `kubectl get -o yaml` gives
+    pub async fn get_yaml(&self, path: &str) -> Result<String> {
+        debug!("GET {}", path);
+        let req = self.request(Method::GET, path).header(header::ACCEPT, YAML);
+        self.send(req).await
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        debug!("GET {}", path);
+        let req = self.request(Method::GET, path).header(header::ACCEPT, JSON);
+        let body = self.send(req).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    pub async fn list<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        debug!("LIST {}", path);
+        let req = self.request(Method::GET, path).header(header::ACCEPT, JSON);
+        let body = self.send(req).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    // start a watch from resource_version, events are read line by line from
+    //  the returned response
+    pub async fn watch(&self, path: &str, resource_version: &str) -> Result<reqwest::Response> {
+        debug!("WATCH {} from {}", path, resource_version);
+        let req = self
+            .request(Method::GET, path)
+            .header(header::ACCEPT, JSON)
+            .query(&[
+                ("watch", "1"),
+                ("resourceVersion", resource_version),
+                ("allowWatchBookmarks", "true"),
+                ("timeoutSeconds", WATCH_TIMEOUT_SECS),
+            ]);
+        let resp = req.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(Error::kube(Some(status.as_u16()), resp.text().await?));
+        }
+        Ok(resp)
+    }
+
+    // JSON merge patch, returns the patched object as yaml
+    pub async fn patch_yaml(&self, path: &str, patch: &serde_json::Value) -> Result<String> {
+        debug!("PATCH {}: {}", path, patch);
+        let req = self
+            .request(Method::PATCH, path)
+            .header(header::ACCEPT, YAML)
+            .header(header::CONTENT_TYPE, MERGE_PATCH)
+            .body(patch.to_string());
+        self.send(req).await
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("server", &self.server)
+            .field("namespace", &self.namespace)
+            .finish()
+    }
+}
+
+// collection paths are cluster wide if namespace is empty
+pub(crate) fn services_path(namespace: &str) -> String {
+    match namespace {
+        "" => "/api/v1/services".to_owned(),
+        ns => format!("/api/v1/namespaces/{}/services", ns),
+    }
+}
+
+pub(crate) fn endpoints_list_path(namespace: &str) -> String {
+    match namespace {
+        "" => "/api/v1/endpoints".to_owned(),
+        ns => format!("/api/v1/namespaces/{}/endpoints", ns),
+    }
+}
+
+pub(crate) fn endpoints_path(namespace: &str, name: &str) -> String {
+    format!("/api/v1/namespaces/{}/endpoints/{}", namespace, name)
+}
+
+pub(crate) fn pod_path(namespace: &str, name: &str) -> String {
+    format!("/api/v1/namespaces/{}/pods/{}", namespace, name)
+}
+
+pub(crate) fn endpointslices_path(namespace: &str) -> String {
+    match namespace {
+        "" => "/apis/discovery.k8s.io/v1/endpointslices".to_owned(),
+        ns => format!("/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices", ns),
+    }
+}
+
+pub(crate) fn endpointslice_path(namespace: &str, name: &str) -> String {
+    format!(
+        "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices/{}",
+        namespace, name
+    )
+}
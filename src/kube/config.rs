@@ -0,0 +1,262 @@
+use crate::error::{Error, Result};
+use log::debug;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+const SA_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const DEFAULT_NAMESPACE: &str = "default";
+
+// Everything needed to talk to an API server, resolved from either a
+// kubeconfig file or the in-cluster service account
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Config {
+    pub server: String,
+    pub namespace: String,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // PEM encoded
+    pub ca_cert: Option<Vec<u8>>,
+    // PEM encoded cert followed by PEM encoded private key
+    pub identity: Option<Vec<u8>>,
+    pub insecure: bool,
+}
+
+impl Config {
+    // in-cluster config if running inside a pod, kubeconfig otherwise,
+    //  which is the same order kubectl uses
+    pub fn infer() -> Result<Self> {
+        if std::env::var("KUBERNETES_SERVICE_HOST").is_ok() {
+            return Self::in_cluster();
+        }
+        Self::from_kubeconfig()
+    }
+
+    pub fn in_cluster() -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| Error::kube(None, "KUBERNETES_SERVICE_HOST not set"))?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        // IPv6 hosts need to be bracketed
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        let dir = Path::new(SA_DIR);
+        let token = std::fs::read_to_string(dir.join("token"))?;
+        let namespace = std::fs::read_to_string(dir.join("namespace"))
+            .unwrap_or_else(|_| DEFAULT_NAMESPACE.to_owned());
+        Ok(Self {
+            server: format!("https://{}:{}", host, port),
+            namespace: namespace.trim().to_owned(),
+            token: Some(token.trim().to_owned()),
+            ca_cert: Some(std::fs::read(dir.join("ca.crt"))?),
+            ..Default::default()
+        })
+    }
+
+    pub fn from_kubeconfig() -> Result<Self> {
+        let path = kubeconfig_path()
+            .ok_or_else(|| Error::kube(None, "no kubeconfig found, set KUBECONFIG or HOME"))?;
+        debug!("loading kubeconfig from {:?}", path);
+        let s = std::fs::read_to_string(&path)?;
+        let kubeconfig = serde_yaml::from_str::<Kubeconfig>(&s)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        kubeconfig.resolve(base)
+    }
+}
+
+// only the first path of KUBECONFIG is used, merging is not supported
+fn kubeconfig_path() -> Option<PathBuf> {
+    if let Ok(paths) = std::env::var("KUBECONFIG") {
+        if let Some(p) = std::env::split_paths(&paths).find(|p| !p.as_os_str().is_empty()) {
+            return Some(p);
+        }
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kube").join("config"))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    current_context: String,
+    #[serde(default)]
+    clusters: Vec<Named<ClusterRepr>>,
+    #[serde(default)]
+    contexts: Vec<Named<ContextRepr>>,
+    #[serde(default)]
+    users: Vec<Named<UserRepr>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Named<T> {
+    name: String,
+    #[serde(alias = "cluster", alias = "context", alias = "user")]
+    value: T,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ClusterRepr {
+    server: String,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContextRepr {
+    cluster: String,
+    user: Option<String>,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct UserRepr {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key: Option<String>,
+    client_key_data: Option<String>,
+}
+
+impl Kubeconfig {
+    fn resolve(self, base: &Path) -> Result<Config> {
+        let current_context = self.current_context;
+        let context = self
+            .contexts
+            .into_iter()
+            .find(|c| c.name == current_context)
+            .ok_or_else(|| {
+                Error::kube(
+                    None,
+                    format!("context {:?} not found in kubeconfig", current_context),
+                )
+            })?
+            .value;
+        let cluster = self
+            .clusters
+            .into_iter()
+            .find(|c| c.name == context.cluster)
+            .ok_or_else(|| {
+                Error::kube(
+                    None,
+                    format!("cluster {:?} not found in kubeconfig", context.cluster),
+                )
+            })?
+            .value;
+        let user = match &context.user {
+            Some(name) => {
+                self.users
+                    .into_iter()
+                    .find(|u| &u.name == name)
+                    .ok_or_else(|| {
+                        Error::kube(None, format!("user {:?} not found in kubeconfig", name))
+                    })?
+                    .value
+            }
+            None => UserRepr::default(),
+        };
+
+        let ca_cert = load(
+            base,
+            &cluster.certificate_authority_data,
+            &cluster.certificate_authority,
+        )?;
+        let cert = load(
+            base,
+            &user.client_certificate_data,
+            &user.client_certificate,
+        )?;
+        let key = load(base, &user.client_key_data, &user.client_key)?;
+        let identity = match (cert, key) {
+            (Some(mut cert), Some(key)) => {
+                cert.push(b'\n');
+                cert.extend(key);
+                Some(cert)
+            }
+            _ => None,
+        };
+        let token = match (user.token, user.token_file) {
+            (Some(token), _) => Some(token),
+            (None, Some(file)) => Some(std::fs::read_to_string(base.join(file))?.trim().to_owned()),
+            _ => None,
+        };
+
+        Ok(Config {
+            server: cluster.server.trim_end_matches('/').to_owned(),
+            namespace: context
+                .namespace
+                .unwrap_or_else(|| DEFAULT_NAMESPACE.to_owned()),
+            token,
+            username: user.username,
+            password: user.password,
+            ca_cert,
+            identity,
+            insecure: cluster.insecure_skip_tls_verify,
+        })
+    }
+}
+
+// kubeconfig fields come in pairs of inline base64 data and a file path
+//  relative to the kubeconfig, inline data wins
+fn load(base: &Path, data: &Option<String>, file: &Option<String>) -> Result<Option<Vec<u8>>> {
+    if let Some(data) = data {
+        let decoded = base64::decode(data.trim())
+            .map_err(|e| Error::kube(None, format!("invalid base64 in kubeconfig: {}", e)))?;
+        return Ok(Some(decoded));
+    }
+    if let Some(file) = file {
+        return Ok(Some(std::fs::read(base.join(file))?));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KUBECONFIG: &str = "
+        apiVersion: v1
+        kind: Config
+        current-context: dev
+        clusters:
+        - name: dev-cluster
+          cluster:
+            server: https://10.0.0.1:6443/
+            insecure-skip-tls-verify: true
+        contexts:
+        - name: prod
+          context:
+            cluster: prod-cluster
+            user: admin
+        - name: dev
+          context:
+            cluster: dev-cluster
+            user: dev-user
+            namespace: ephc
+        users:
+        - name: dev-user
+          user:
+            token: abc
+            client-certificate-data: Y2VydA==
+            client-key-data: a2V5";
+
+    #[test]
+    fn resolve_kubeconfig() {
+        let kubeconfig = serde_yaml::from_str::<Kubeconfig>(KUBECONFIG).unwrap();
+        let cfg = kubeconfig.resolve(Path::new("/nonexistent")).unwrap();
+        assert_eq!(cfg.server, "https://10.0.0.1:6443");
+        assert_eq!(cfg.namespace, "ephc");
+        assert_eq!(cfg.token.as_deref(), Some("abc"));
+        assert_eq!(cfg.identity.as_deref(), Some(&b"cert\nkey"[..]));
+        assert!(cfg.insecure);
+    }
+}
@@ -0,0 +1,110 @@
+use super::yaml::{EndpointSliceRepr, ServiceRepr, SliceEndpointRepr};
+
+// Addresses removed from (-) and added to (+) each subset, one per line.
+// Subsets are compared by index, which is stable as we never add or remove
+//  a subset.
+pub(crate) fn endpoints(old: &ServiceRepr, new: &ServiceRepr) -> Vec<String> {
+    let mut lines = vec![];
+    let empty = vec![];
+    for k in 0..old.subsets.len().max(new.subsets.len()) {
+        let old = old.subsets.get(k).map_or(&empty, |s| &s.addresses);
+        let new = new.subsets.get(k).map_or(&empty, |s| &s.addresses);
+        for addr in old.iter().filter(|a| !new.iter().any(|b| b.ip == a.ip)) {
+            lines.push(format!("subsets[{}].addresses: -{}", k, addr.ip));
+        }
+        for addr in new.iter().filter(|a| !old.iter().any(|b| b.ip == a.ip)) {
+            lines.push(format!("subsets[{}].addresses: +{}", k, addr.ip));
+        }
+    }
+    lines
+}
+
+// Endpoints of a slice whose conditions changed, one per line
+pub(crate) fn slice(old: &EndpointSliceRepr, new: &EndpointSliceRepr) -> Vec<String> {
+    let mut lines = vec![];
+    for ep in &new.endpoints {
+        let before = old.endpoints.iter().find(|o| o.addresses == ep.addresses);
+        match before {
+            Some(before) if before.conditions == ep.conditions => {}
+            Some(before) => lines.push(format!(
+                "{} {}: {} -> {}",
+                old.metadata.name,
+                ep.addresses.join(","),
+                conditions(before),
+                conditions(ep)
+            )),
+            None => lines.push(format!(
+                "{} {}: +{}",
+                old.metadata.name,
+                ep.addresses.join(","),
+                conditions(ep)
+            )),
+        }
+    }
+    for ep in &old.endpoints {
+        if !new.endpoints.iter().any(|n| n.addresses == ep.addresses) {
+            lines.push(format!(
+                "{} {}: -{}",
+                old.metadata.name,
+                ep.addresses.join(","),
+                conditions(ep)
+            ));
+        }
+    }
+    lines
+}
+
+fn conditions(ep: &SliceEndpointRepr) -> String {
+    let show = |c: Option<bool>| c.map_or("unset".to_owned(), |c| c.to_string());
+    format!(
+        "ready={} serving={}",
+        show(ep.conditions.ready),
+        show(ep.conditions.serving)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn endpoints_diff() {
+        let old = ServiceRepr::from_str(
+            "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          - ip: 172.0.1.5
+          ports:
+          - port: 80
+            protocol: TCP
+        - addresses:
+          - ip: 172.0.1.6
+          ports:
+          - port: 81
+            protocol: TCP",
+        )
+        .unwrap();
+        let mut new = old.clone();
+        assert!(endpoints(&old, &new).is_empty());
+
+        new.subsets[0].addresses.remove(1);
+        new.subsets[1]
+            .addresses
+            .push(old.subsets[0].addresses[1].clone());
+        assert_eq!(
+            endpoints(&old, &new),
+            vec![
+                "subsets[0].addresses: -172.0.1.5",
+                "subsets[1].addresses: +172.0.1.5"
+            ]
+        );
+    }
+}
@@ -1,5 +1,11 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    time::{Instant, SystemTime},
+};
 
+// named after the protocol strings in k8s
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Protocol {
     TCP,
@@ -36,16 +42,72 @@ pub(crate) struct Counter {
     pub down: u32,
 }
 
+// where an endpoint is in its probe schedule
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProbeState {
+    // None until it's scheduled for the first time
+    pub next: Option<Instant>,
+    // probed, the result not applied yet
+    pub in_flight: bool,
+    // failed probes in a row since it was removed
+    pub backoff: u32,
+    // None until a probe result is applied
+    pub last: Option<ProbeResult>,
+}
+
+// outcome of the latest probe of an endpoint
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeResult {
+    pub at: SystemTime,
+    // None if the endpoint was healthy
+    pub error: Option<String>,
+}
+
+// set by an operator, overrides what probes find until it expires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Override {
+    // kept out of k8s, probes won't restore it
+    Drained,
+    // kept in k8s, probes won't remove it
+    Restored,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Admin {
+    pub state: Override,
+    // None if it's kept until released
+    pub until: Option<Instant>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Endpoint {
     pub addr: SocketAddr,
     pub protocol: Protocol,
+    // name of the port in the service, if it's got one
+    pub port_name: Option<String>,
+    pub app_protocol: Option<String>,
+    // index of the subset, or the EndpointSlice, this endpoint came from
+    pub subset: usize,
     pub status: EndpointStatus,
     pub counter: Counter,
     pub threshold: Threshold,
+    pub probe_state: ProbeState,
+    pub admin: Option<Admin>,
 }
 
 impl Endpoint {
+    // the override in effect at now, an expired one is dropped
+    pub fn admin_override(&mut self, now: Instant) -> Option<Override> {
+        match &self.admin {
+            Some(admin) if admin.until.is_some_and(|until| until <= now) => {
+                self.admin = None;
+                None
+            }
+            Some(admin) => Some(admin.state),
+            None => None,
+        }
+    }
+
     pub fn up(&mut self) -> bool {
         match self.status {
             EndpointStatus::Removed => {
@@ -81,6 +143,7 @@ impl Endpoint {
 
     pub fn set_status(&mut self, status: EndpointStatus) {
         self.reset_counter();
+        self.probe_state.backoff = 0;
         self.status = status;
     }
 }
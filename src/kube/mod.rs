@@ -1,16 +1,25 @@
+use crate::config::Config;
 use crate::error::Result;
-use log::error;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use log::{debug, error, info, log_enabled, Level};
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::SystemTime;
 use tokio::sync::RwLock;
 
+mod annotation;
+mod client;
+mod config;
+mod diff;
 mod endpoint;
 mod service;
+mod slice;
+mod state;
+mod watch;
 pub mod yaml;
 
+pub(crate) use annotation::Annotations;
+pub(crate) use client::Client;
+pub(crate) use watch::watch;
 // TODO
 // glob import doesn't reexport anything because no candidate is public enough
 //  is this a bug?
@@ -18,120 +27,488 @@ pub mod yaml;
 pub use endpoint::*;
 #[allow(unused_imports)]
 pub use service::*;
+pub(crate) use slice::Slices;
 
-fn exec(cmdline: &str) -> Result<String> {
-    let mut cmd = Command::new("bash");
-    let cmd = cmd.arg("-c").arg(cmdline);
-
-    let output = cmd
-        .stdout(Stdio::piped())
-        .output()
-        .expect("failed to execute process");
-
-    let status = cmd.status()?;
-    if !status.success() {
-        let err = String::from_utf8_lossy(&output.stderr[..]);
-        error!(
-            "failed to run cmd: exit status: {}, stderr: {}",
-            status, err
+// all services being checked, by namespace/name
+pub(crate) type Services = Arc<RwLock<HashMap<String, Arc<RwLock<Service>>>>>;
+
+// which namespaces to check services in
+#[derive(Debug, Clone)]
+pub(crate) enum Namespaces {
+    All,
+    Some(Vec<String>),
+}
+
+impl Namespaces {
+    // namespaces to list from, an empty one means cluster wide
+    fn scopes(&self) -> Vec<String> {
+        match self {
+            Namespaces::All => vec!["".to_owned()],
+            Namespaces::Some(namespaces) => namespaces.clone(),
+        }
+    }
+}
+
+// where endpoints of services are read from and written to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Backend {
+    Endpoints,
+    EndpointSlices,
+}
+
+impl FromStr for Backend {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "endpoints" => Ok(Self::Endpoints),
+            "endpointslices" => Ok(Self::EndpointSlices),
+            _ => Err(Self::Err::new("unknown backend")),
+        }
+    }
+}
+
+// which services to check and how to find their endpoints
+#[derive(Debug, Clone)]
+pub(crate) struct Selector {
+    pub namespaces: Namespaces,
+    pub allow: Option<Vec<String>>,
+    pub block: Option<Vec<String>>,
+    pub backend: Backend,
+}
+
+// key of a service in Services
+pub(crate) fn key(namespace: &str, name: &str) -> String {
+    format!("{}/{}", namespace, name)
+}
+
+// write the subsets of repr back to k8s, returns the new resourceVersion
+async fn apply_svc(client: &Client, repr: &yaml::ServiceRepr) -> Result<String> {
+    let path = client::endpoints_path(&repr.metadata.namespace, &repr.metadata.name);
+    if log_enabled!(Level::Debug) {
+        debug!("applying endpoints:\n{}", repr.to_yaml()?);
+    }
+    // merge patch replaces lists as a whole, so this is what `kubectl apply`
+    //  would do to subsets without touching anything else of the object, a
+    //  null annotation deletes it. As with slices, it fails on conflict
+    //  rather than overwrite what the endpoints controller wrote since.
+    let patch = serde_json::json!({
+        "metadata": {
+            "resourceVersion": repr.metadata.resource_version,
+            "annotations": {
+                state::REMOVED_ANNOTATION: repr.metadata.annotations.get(state::REMOVED_ANNOTATION),
+            },
+        },
+        "subsets": repr.subsets,
+    });
+    let yml = client.patch_yaml(&path, &patch).await.inspect_err(|_| {
+        crate::metrics::apply_error(&repr.metadata.namespace, &repr.metadata.name)
+    })?;
+    let new_svc = yaml::ServiceRepr::from_str(&yml)?;
+    Ok(new_svc.metadata.resource_version)
+}
+
+// write the endpoints of an EndpointSlice back to k8s, returns the new
+//  resourceVersion
+async fn apply_slice(client: &Client, slice: &yaml::EndpointSliceRepr) -> Result<String> {
+    let path = client::endpointslice_path(&slice.metadata.namespace, &slice.metadata.name);
+    // slices are shared with their controller, fail on conflict rather than
+    //  overwrite what it just wrote
+    let patch = serde_json::json!({
+        "metadata": {
+            "resourceVersion": slice.metadata.resource_version,
+            "annotations": {
+                state::REMOVED_ANNOTATION: slice.metadata.annotations.get(state::REMOVED_ANNOTATION),
+            },
+        },
+        "endpoints": slice.endpoints,
+    });
+    let yml = client.patch_yaml(&path, &patch).await.inspect_err(|_| {
+        if let Some(svc) = slice.metadata.labels.get(slice::SERVICE_NAME_LABEL) {
+            crate::metrics::apply_error(&slice.metadata.namespace, svc);
+        }
+    })?;
+    let new_slice = serde_yaml::from_str::<yaml::EndpointSliceRepr>(&yml)?;
+    Ok(new_slice.metadata.resource_version)
+}
+
+// Write new in place of old in k8s and return the new resourceVersion, in
+//  dry run mode the change is only logged and the version of old returned
+async fn write_svc(
+    client: &Client,
+    old: &yaml::ServiceRepr,
+    new: &yaml::ServiceRepr,
+) -> Result<String> {
+    if client.dry_run {
+        info!(
+            "dry run, not applying endpoints {}/{}:\n{}",
+            new.metadata.namespace,
+            new.metadata.name,
+            diff::endpoints(old, new).join("\n")
         );
-        return Err(crate::error::Error::from(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            err,
-        )));
+        return Ok(old.metadata.resource_version.clone());
     }
+    apply_svc(client, new).await
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout[..]).to_string();
-    Ok(stdout)
+// write_svc for an EndpointSlice
+async fn write_slice(
+    client: &Client,
+    old: &yaml::EndpointSliceRepr,
+    new: &yaml::EndpointSliceRepr,
+) -> Result<String> {
+    if client.dry_run {
+        info!(
+            "dry run, not applying endpointslice {}/{}:\n{}",
+            new.metadata.namespace,
+            new.metadata.name,
+            diff::slice(old, new).join("\n")
+        );
+        return Ok(old.metadata.resource_version.clone());
+    }
+    apply_slice(client, new).await
 }
 
-fn apply_svc(name: &str, yml: &str) -> Result<String> {
-    let t = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => n.as_secs(),
-        Err(e) => {
-            error!("failed to get time: {}", e);
-            0
+// Services of items, one that can't be got, such as a selectorless service
+//  without Endpoints, is skipped rather than failing all of them
+async fn get_svcs(
+    client: Arc<Client>,
+    backend: Backend,
+    config: &Config,
+    items: Vec<yaml::ServiceItemRepr>,
+) -> Vec<Arc<RwLock<Service>>> {
+    let mut svcs = Vec::<Arc<RwLock<Service>>>::new();
+    for item in items {
+        match get_svc(client.clone(), backend, config, &item).await {
+            Ok(Some(svc)) => svcs.push(Arc::new(RwLock::new(svc))),
+            Ok(None) => {}
+            Err(e) => error!(
+                "failed to get service {}: {}",
+                key(&item.metadata.namespace, &item.metadata.name),
+                e
+            ),
         }
-    };
-    let fname = format!("/tmp/ephc_{}_{}", t, name);
-    let mut file = std::fs::File::create(&fname)?;
-    file.write_all(yml.as_bytes())?;
-
-    exec(&format!("set -eo pipefail; kubectl apply -f {}", &fname))?;
-
-    // get new version after apply
-    // all errors are not propagated
-    let yml = match get_svc_repr(name) {
-        Err(e) => {
-            error!("failed to get service repr for {}: {}", name, e);
-            return Ok("0".to_owned());
+    }
+    svcs
+}
+
+async fn get_svc(
+    client: Arc<Client>,
+    backend: Backend,
+    config: &Config,
+    item: &yaml::ServiceItemRepr,
+) -> Result<Option<Service>> {
+    let (ns, n) = (&item.metadata.namespace, &item.metadata.name);
+    let cfg = config.service(ns, n);
+    let svc = match backend {
+        Backend::Endpoints => {
+            let yml_str = get_svc_repr(&client, ns, n).await?;
+            Service::new(yml_str, cfg.threshold.clone(), cfg.alerter.clone(), client)?
         }
-        Ok(yml) => yml,
-    };
-    let new_svc = match yaml::ServiceRepr::from_str(&yml) {
-        Ok(repr) => repr,
-        Err(e) => {
-            error!("failed to parse yaml for repr {}: {}", name, e);
-            return Ok("0".to_owned());
+        Backend::EndpointSlices => {
+            let slices = get_svc_slices(&client, ns, n).await?;
+            Service::from_slices(
+                ns,
+                n,
+                slices,
+                cfg.threshold.clone(),
+                cfg.alerter.clone(),
+                client,
+            )?
         }
     };
-    Ok(new_svc.metadata.resource_version)
+    Ok(svc.map(|mut svc| {
+        svc.annotate(&item.metadata.annotations, &cfg);
+        svc
+    }))
 }
 
-pub(crate) fn get_svcs(
-    allow: &Option<Vec<String>>,
-    block: &Option<Vec<String>>,
-    t: Threshold,
-    alerter: Arc<crate::alert::Alert>,
-) -> Result<Vec<Arc<RwLock<Service>>>> {
-    let names: Vec<String> = match allow {
-        Some(allow) => allow.iter().map(|n| (*n).to_owned()).collect(),
-        None => get_svc_names(block)?,
+// Bring svcs in line with k8s by listing every service, those no longer
+//  selected, or gone, are released
+pub(crate) async fn refresh(
+    client: Arc<Client>,
+    selector: &Selector,
+    config: &Config,
+    svcs: &Services,
+) -> Result<()> {
+    let items = get_svc_items(&client, selector).await?;
+    let stale: Vec<String> = svcs
+        .read()
+        .await
+        .keys()
+        .filter(|k| {
+            !items
+                .iter()
+                .any(|item| key(&item.metadata.namespace, &item.metadata.name) == **k)
+        })
+        .cloned()
+        .collect();
+    for key in stale {
+        release_svc(svcs, &key).await;
+    }
+    let res = get_svcs(client, selector.backend, config, items).await;
+    for svc in res {
+        upsert_svc(svcs, svc).await;
+    }
+    Ok(())
+}
+
+// stop checking the service of key, putting back what we removed from it
+pub(crate) async fn release_svc(svcs: &Services, key: &str) {
+    let svc = match svcs.write().await.remove(key) {
+        Some(svc) => svc,
+        None => return,
     };
-    let mut svcs = Vec::<Arc<RwLock<Service>>>::new();
-    for n in names {
-        // let svc = get_svc(n, t.clone())?;
-        let yml_str = get_svc_repr(&n)?;
-        let svc = Service::new(yml_str, t.clone(), alerter.clone())?;
-        if svc.is_none() {
-            continue;
+    info!("stop checking service {}", key);
+    let mut svc = svc.write().await;
+    crate::metrics::endpoints_gone(
+        &svc.namespace,
+        &svc.name,
+        svc.endpoints.iter().map(|ep| &ep.addr),
+    );
+    if let Err(e) = svc.restore_all().await {
+        error!("failed to restore eps of {}: {}", key, e);
+    }
+}
+
+// apply config to every service in svcs
+pub(crate) async fn reconfigure(svcs: &Services, config: &Config) {
+    let svcs: Vec<_> = svcs.read().await.values().cloned().collect();
+    for svc in svcs {
+        let mut svc = svc.write().await;
+        let cfg = config.service(&svc.namespace, &svc.name);
+        svc.reconfigure(&cfg);
+    }
+}
+
+// Insert svc unless the one we have is already up to date. The lock of svcs
+//  is only taken to look up and to insert, never while waiting for the lock
+//  of a service, which is held across writes to k8s.
+pub(crate) async fn upsert_svc(svcs: &Services, svc: Arc<RwLock<Service>>) {
+    let svc_clone = svc.clone();
+    // not shared with anything yet
+    let mut svc_writer = svc_clone.write().await;
+    let key = svc_writer.key();
+    let old = svcs.read().await.get(&key).cloned();
+    let old = match old {
+        Some(old) => old,
+        None => {
+            report_annotations(&svc_writer);
+            svcs.write().await.insert(key, svc.clone());
+            prune_gone(svc);
+            return;
+        }
+    };
+    let old_reader = old.read().await;
+    if svc_writer.our_version == old_reader.our_version {
+        if svc_writer.annotations.source == old_reader.annotations.source {
+            debug!("service {} not changed", svc_writer.key());
+            return;
+        }
+        // only the annotations of the Service object changed
+        info!("annotations of service {} changed", svc_writer.key());
+        drop(old_reader);
+        report_annotations(&svc_writer);
+        let mut old_writer = old.write().await;
+        old_writer.annotations = svc_writer.annotations.clone();
+        if let Some(ep) = svc_writer.endpoints.first() {
+            for old_ep in &mut old_writer.endpoints {
+                old_ep.threshold = ep.threshold.clone();
+            }
+        }
+        return;
+    }
+    // versions of services built from several slices can't be ordered
+    if let (Ok(version), Ok(old_version)) = (
+        svc_writer.our_version.parse::<u64>(),
+        old_reader.our_version.parse::<u64>(),
+    ) {
+        if version < old_version {
+            error!(
+                "service {} got version {} under our version {}",
+                svc_writer.key(),
+                version,
+                old_version
+            );
+            return;
         }
-        svcs.push(Arc::new(RwLock::new(svc.unwrap())))
     }
-    Ok(svcs)
+
+    info!(
+        "service {} changed from outside, replacing",
+        svc_writer.key()
+    );
+    debug!(
+        "new version: {}, our version: {}",
+        svc_writer.our_version, old_reader.our_version
+    );
+    report_annotations(&svc_writer);
+    svc_writer.carry_over(&old_reader);
+    crate::metrics::endpoints_gone(
+        &old_reader.namespace,
+        &old_reader.name,
+        old_reader
+            .endpoints
+            .iter()
+            .map(|ep| &ep.addr)
+            .filter(|addr| !svc_writer.endpoints.iter().any(|ep| ep.addr == **addr)),
+    );
+    drop(old_reader);
+    let mut svcs_writer = svcs.write().await;
+    // released while the lock of old was waited for, it's not to come back
+    if !svcs_writer
+        .get(&key)
+        .is_some_and(|current| Arc::ptr_eq(current, &old))
+    {
+        return;
+    }
+    svcs_writer.insert(key, svc.clone());
+    drop(svcs_writer);
+    prune_gone(svc);
+}
+
+// Addresses we removed come back as removed endpoints whenever a service is
+//  built, those whose pods are gone since are forgotten in the background
+fn prune_gone(svc: Arc<RwLock<Service>>) {
+    tokio::spawn(async move {
+        let mut svc = svc.write().await;
+        if let Err(e) = svc.prune_gone().await {
+            error!("failed to prune removed eps of {}: {}", svc.key(), e);
+        }
+    });
+}
+
+// Invalid annotations are reported once per version of a service picked up
+fn report_annotations(svc: &Service) {
+    let errors = &svc.annotations.errors;
+    if errors.is_empty() {
+        return;
+    }
+    for e in errors {
+        error!("ignoring annotation of {}: {}", svc.key(), e);
+    }
+    svc.alerter.alert(crate::alert::Msg::InvalidAnnotations(
+        svc.namespace.clone(),
+        svc.name.clone(),
+        errors.clone(),
+    ));
 }
 
-fn get_svc_names(block: &Option<Vec<String>>) -> Result<Vec<String>> {
+// Restore the original endpoints of every service with removed ones, all at
+//  once so that a slow API server holds up as few of them as possible
+pub(crate) async fn restore_all(svcs: &Services) {
+    let svcs: Vec<_> = svcs.read().await.values().cloned().collect();
+    let jhs: Vec<_> = svcs
+        .into_iter()
+        .map(|svc| {
+            tokio::spawn(async move {
+                let mut svc = svc.write().await;
+                if let Err(e) = svc.restore_all().await {
+                    error!("failed to restore eps of {}: {}", svc.key(), e);
+                }
+            })
+        })
+        .collect();
+    for jh in jhs {
+        let _ = jh.await;
+    }
+}
+
+// every service to check
+async fn get_svc_items(client: &Client, selector: &Selector) -> Result<Vec<yaml::ServiceItemRepr>> {
+    let mut items = vec![];
+    for ns in selector.namespaces.scopes() {
+        let list = client
+            .list::<yaml::ServiceListRepr>(&client::services_path(&ns))
+            .await?;
+        items.extend(filter_svc_items(list, &selector.allow, &selector.block));
+    }
+    Ok(items)
+}
+
+fn filter_svc_items(
+    list: yaml::ServiceListRepr,
+    allow: &Option<Vec<String>>,
+    block: &Option<Vec<String>>,
+) -> Vec<yaml::ServiceItemRepr> {
+    list.items
+        .into_iter()
+        .filter(|svc| should_check(svc, allow, block))
+        .collect()
+}
+
+// Services in the allow list are checked no matter what, otherwise only
+//  ClusterIP services not in the block list are checked
+fn should_check(
+    svc: &yaml::ServiceItemRepr,
+    allow: &Option<Vec<String>>,
+    block: &Option<Vec<String>>,
+) -> bool {
+    let (ns, name) = (&svc.metadata.namespace, &svc.metadata.name);
+    if let Some(allow) = allow {
+        return in_list(allow, ns, name);
+    }
     let default_block_list = &vec!["kubernetes".to_owned()];
     let block = match block {
         Some(l) => l,
         None => default_block_list,
     };
+    svc.spec.type_ == "ClusterIP" && !in_list(block, ns, name)
+}
 
-    let stdout = exec("set -eo pipefail; kubectl get svc | grep ClusterIP | gawk '{print $1}'")?;
-    let mut lines: Vec<String> = stdout.lines().map(|el| el.to_owned()).collect();
-    lines.retain(|el| !block.contains(el));
-    Ok(lines)
+// list entries are either namespace/name or a name in any namespace
+fn in_list(list: &[String], namespace: &str, name: &str) -> bool {
+    list.iter().any(|el| match el.split_once('/') {
+        Some((ns, n)) => ns == namespace && n == name,
+        None => el == name,
+    })
 }
 
-fn get_svc_repr(svc_name: &str) -> Result<String> {
-    exec(&format!(
-        "set -eo pipefail; kubectl get ep {} -o yaml",
-        svc_name
-    ))
+async fn get_svc_repr(client: &Client, namespace: &str, svc_name: &str) -> Result<String> {
+    client
+        .get_yaml(&client::endpoints_path(namespace, svc_name))
+        .await
 }
 
-// fn get_svc(svc_name: String, t: Threshold) -> Result<Option<Service>> {
-//     let yml_str = get_svc_repr(&svc_name)?;
-//     Service::new(yml_str, t)
-// }
+async fn get_svc_slices(
+    client: &Client,
+    namespace: &str,
+    svc_name: &str,
+) -> Result<Vec<yaml::EndpointSliceRepr>> {
+    let selector = format!("{}={}", slice::SERVICE_NAME_LABEL, svc_name);
+    let path = format!(
+        "{}?labelSelector={}",
+        client::endpointslices_path(namespace),
+        url::form_urlencoded::byte_serialize(selector.as_bytes()).collect::<String>()
+    );
+    let list = client.list::<yaml::EndpointSliceListRepr>(&path).await?;
+    Ok(list.items)
+}
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::str::FromStr;
     use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    // a client that never gets to talk to any server
+    pub(crate) fn client() -> Arc<super::Client> {
+        let cfg = super::config::Config {
+            server: "https://127.0.0.1:6443".to_owned(),
+            namespace: "default".to_owned(),
+            ..Default::default()
+        };
+        Arc::new(super::Client::new(cfg).unwrap())
+    }
 
-    const YML_STR: &str = "
+    // nothing listens on its server either, it'd fail if anything was written
+    pub(crate) fn dry_run_client() -> Arc<super::Client> {
+        let mut client = Arc::try_unwrap(client()).ok().unwrap();
+        client.dry_run = true;
+        Arc::new(client)
+    }
+
+    pub(crate) const YML_STR: &str = "
         apiVersion: v1
         kind: Endpoints
         metadata:
@@ -157,9 +534,116 @@ mod tests {
             port: 31001
             protocol: TCP";
 
+    const SVC_LIST: &str = r#"{
+        "kind": "ServiceList",
+        "apiVersion": "v1",
+        "items": [
+            {"metadata": {"name": "kubernetes", "namespace": "default"}, "spec": {"type": "ClusterIP"}},
+            {"metadata": {"name": "api", "namespace": "default"}, "spec": {"type": "ClusterIP"}},
+            {"metadata": {"name": "ext", "namespace": "default"}, "spec": {"type": "ExternalName"}},
+            {"metadata": {"name": "web", "namespace": "default"}, "spec": {"type": "ClusterIP"}},
+            {"metadata": {"name": "web", "namespace": "prod"}, "spec": {"type": "ClusterIP"}}
+        ]
+    }"#;
+
+    #[tokio::test]
+    #[ignore = "requires a kubernetes cluster"]
+    async fn get_svc_items() {
+        let client = super::Client::infer().unwrap();
+        let selector = super::Selector {
+            namespaces: super::Namespaces::Some(vec![client.namespace.clone()]),
+            allow: None,
+            block: None,
+            backend: super::Backend::Endpoints,
+        };
+        super::get_svc_items(&client, &selector).await.unwrap();
+    }
+
     #[test]
-    fn get_svc_names() {
-        super::get_svc_names(&None).unwrap();
+    fn filter_svc_items() {
+        let names = |allow: Option<Vec<&str>>, block: Option<Vec<&str>>| {
+            let list = serde_json::from_str::<super::yaml::ServiceListRepr>(SVC_LIST).unwrap();
+            let to_vec = |l: Vec<&str>| l.into_iter().map(|el| el.to_owned()).collect();
+            super::filter_svc_items(list, &allow.map(to_vec), &block.map(to_vec))
+                .into_iter()
+                .map(|svc| super::key(&svc.metadata.namespace, &svc.metadata.name))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(None, None),
+            vec!["default/api", "default/web", "prod/web"]
+        );
+        assert_eq!(
+            names(None, Some(vec!["default/web"])),
+            vec!["default/kubernetes", "default/api", "prod/web"]
+        );
+        assert_eq!(
+            names(Some(vec!["web", "default/ext"]), None),
+            vec!["default/ext", "default/web", "prod/web"]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_svcs_skips_failed() {
+        // nothing listens on the server of client
+        let config = crate::config::Config::new(Default::default());
+        let items =
+            vec![
+                serde_json::from_str(r#"{"metadata": {"name": "api", "namespace": "default"}}"#)
+                    .unwrap(),
+            ];
+        let svcs = super::get_svcs(client(), super::Backend::Endpoints, &config, items).await;
+        assert!(svcs.is_empty());
+    }
+
+    #[test]
+    fn service_new_multiple_subsets() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: ephc-test
+          namespace: default
+          resourceVersion: \"82479279\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          - ip: 172.0.1.5
+          ports:
+          - name: http
+            port: 8080
+            protocol: TCP
+        - addresses:
+          - ip: 172.0.1.6
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP
+          - name: admin
+            port: 81
+            protocol: TCP";
+        let svc = super::Service::new(
+            String::from(yml_str),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            super::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        let eps = svc
+            .endpoints
+            .iter()
+            .map(|ep| (ep.subset, ep.addr.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            eps,
+            vec![
+                (0, "172.0.1.4:8080".to_owned()),
+                (0, "172.0.1.5:8080".to_owned()),
+                (1, "172.0.1.6:80".to_owned()),
+                (1, "172.0.1.6:81".to_owned()),
+            ]
+        );
     }
 
     #[test]
@@ -178,7 +662,491 @@ mod tests {
             String::from(YML_STR),
             threshold,
             Arc::new(crate::alert::Alert::default()),
+            super::tests::client(),
         );
         println!("{:?}", svc);
     }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let mut svc = super::Service::new(
+            String::from(YML_STR),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+
+        svc.remove_ep(0).await.unwrap();
+        assert_eq!(svc.our_version, "82479279");
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 2);
+        assert_eq!(svc.endpoints[0].status, super::EndpointStatus::Removed);
+
+        // put back once every port of the address is up
+        for i in [0, 3, 6] {
+            svc.restore_ep(i).await.unwrap();
+        }
+        assert_eq!(svc.our_version, "82479279");
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 3);
+        assert_eq!(svc.endpoints[0].status, super::EndpointStatus::Healthy);
+    }
+
+    #[tokio::test]
+    async fn restore_all() {
+        let mut svc = super::Service::new(
+            String::from(YML_STR),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.remove_ep(0).await.unwrap();
+        svc.remove_ep(1).await.unwrap();
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 1);
+
+        let svcs: super::Services = Default::default();
+        svcs.write()
+            .await
+            .insert(svc.key(), Arc::new(RwLock::new(svc)));
+        super::restore_all(&svcs).await;
+        let svc = svcs.read().await.values().next().unwrap().clone();
+        let svc = svc.read().await;
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 3);
+        assert!(svc
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == super::EndpointStatus::Healthy));
+    }
+
+    #[tokio::test]
+    async fn service_new_reconciles_removed() {
+        let mut svc = super::Service::new(
+            String::from(YML_STR),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.remove_ep(0).await.unwrap();
+
+        // as if ephc restarted and got the pruned object from k8s
+        let svc = super::Service::new(
+            svc.repr.to_yaml().unwrap(),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 2);
+        let removed: Vec<_> = svc
+            .endpoints
+            .iter()
+            .filter(|ep| ep.status == super::EndpointStatus::Removed)
+            .map(|ep| ep.addr.to_string())
+            .collect();
+        assert_eq!(
+            removed,
+            vec!["172.0.1.4:31000", "172.0.1.4:31002", "172.0.1.4:31001"]
+        );
+        assert_eq!(svc.endpoints.len(), 9);
+    }
+
+    #[tokio::test]
+    async fn upsert_carries_over() {
+        let new = |version: &str| {
+            let svc = super::Service::new(
+                YML_STR.replace("82479279", version),
+                super::Threshold {
+                    restore: 3,
+                    remove: 3,
+                },
+                Arc::new(crate::alert::Alert::default()),
+                client(),
+            )
+            .unwrap()
+            .unwrap();
+            Arc::new(RwLock::new(svc))
+        };
+        let old = new("1");
+        {
+            let mut old = old.write().await;
+            old.endpoints[0].admin = Some(super::Admin {
+                state: super::Override::Drained,
+                until: None,
+            });
+            old.endpoints[1].down();
+            old.endpoints[1].probe_state.in_flight = true;
+        }
+        let svcs: super::Services = Default::default();
+        super::upsert_svc(&svcs, old).await;
+        super::upsert_svc(&svcs, new("2")).await;
+
+        let svc = svcs.read().await.values().next().unwrap().clone();
+        let svc = svc.read().await;
+        assert_eq!(svc.our_version, "2");
+        assert_eq!(
+            svc.endpoints[0].admin.as_ref().map(|admin| admin.state),
+            Some(super::Override::Drained)
+        );
+        assert_eq!(svc.endpoints[1].counter.down, 1);
+        assert!(!svc.endpoints[1].probe_state.in_flight);
+    }
+
+    #[tokio::test]
+    async fn upsert_leaves_svcs_unlocked() {
+        let new = |version: &str| {
+            let svc = super::Service::new(
+                YML_STR.replace("82479279", version),
+                super::Threshold::default(),
+                Arc::new(crate::alert::Alert::default()),
+                client(),
+            )
+            .unwrap()
+            .unwrap();
+            Arc::new(RwLock::new(svc))
+        };
+        let svcs: super::Services = Default::default();
+        let old = new("1");
+        super::upsert_svc(&svcs, old.clone()).await;
+
+        // as if a write to k8s of old hung
+        let old_writer = old.write().await;
+        let jh = tokio::spawn({
+            let svcs = svcs.clone();
+            async move { super::upsert_svc(&svcs, new("2")).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let other = tokio::time::timeout(std::time::Duration::from_millis(50), svcs.write())
+            .await
+            .is_ok();
+        assert!(other);
+        drop(old_writer);
+        jh.await.unwrap();
+        let svc = svcs.read().await.values().next().unwrap().clone();
+        assert_eq!(svc.read().await.our_version, "2");
+    }
+
+    // A client of an API server answering with respond, which is given the
+    //  request line, such as "GET /api/v1/...", and the body of a request
+    pub(crate) async fn api_server<F>(respond: F) -> super::Client
+    where
+        F: Fn(&str, &str) -> (&'static str, String) + Send + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                // the head, then as much of the body as it says there is
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let s = String::from_utf8_lossy(&req).into_owned();
+                    let (head, body) = match s.split_once("\r\n\r\n") {
+                        Some((head, body)) => (head.to_owned(), body.to_owned()),
+                        None if n > 0 => continue,
+                        None => (s, String::new()),
+                    };
+                    let len = head
+                        .lines()
+                        .filter_map(|line| line.split_once(": "))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, v)| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= len || n == 0 {
+                        break (head, body);
+                    }
+                };
+                let line = head.lines().next().unwrap_or_default();
+                let (status, body) = respond(line, &body);
+                let resp = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        let cfg = super::config::Config {
+            server: format!("http://{}", addr),
+            namespace: "default".to_owned(),
+            ..Default::default()
+        };
+        super::Client::new(cfg).unwrap()
+    }
+
+    #[tokio::test]
+    async fn write_conflict() {
+        // versions other than the first one are taken
+        let client = api_server(|line, body| {
+            if line.starts_with("GET ") {
+                return ("200 OK", YML_STR.replace("82479279", "2"));
+            }
+            let patch: serde_json::Value = serde_json::from_str(body).unwrap();
+            match patch["metadata"]["resourceVersion"].as_str() {
+                Some("82479279") => ("409 Conflict", "{}".to_owned()),
+                Some(v) => {
+                    let next = (v.parse::<u64>().unwrap() + 1).to_string();
+                    ("200 OK", YML_STR.replace("82479279", &next))
+                }
+                None => ("400 Bad Request", "{}".to_owned()),
+            }
+        })
+        .await;
+        let mut svc = super::Service::new(
+            YML_STR.to_owned(),
+            super::Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            Arc::new(client),
+        )
+        .unwrap()
+        .unwrap();
+        svc.endpoints[1].down();
+
+        // changed from outside, nothing is removed and the new one is taken
+        let e = svc.remove_ep(0).await.unwrap_err();
+        assert_eq!(e.kube_code(), Some(409));
+        assert_eq!(svc.our_version, "2");
+        assert!(svc
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == super::EndpointStatus::Healthy));
+        assert_eq!(svc.endpoints[1].counter.down, 1);
+
+        // each write is on top of the version the last one gave
+        svc.remove_ep(0).await.unwrap();
+        assert_eq!(svc.our_version, "3");
+        // the original is written on top of the last one too
+        svc.restore_all().await.unwrap();
+        assert_eq!(svc.our_version, "4");
+    }
+
+    // an API server which only knows of pod api-2, of uid 2
+    async fn pods_server() -> Arc<super::Client> {
+        let mut client = api_server(|line, _| {
+            if line.starts_with("GET /api/v1/namespaces/default/pods/api-2 ") {
+                ("200 OK", r#"{"metadata": {"uid": "2"}}"#.to_owned())
+            } else {
+                ("404 Not Found", "{}".to_owned())
+            }
+        })
+        .await;
+        client.dry_run = true;
+        Arc::new(client)
+    }
+
+    // addresses of pods api-0 to api-2, and one of no pod
+    const PODS_YML_STR: &str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+            targetRef: {kind: Pod, namespace: default, name: api-0, uid: \"0\"}
+          - ip: 172.0.1.5
+            targetRef: {kind: Pod, namespace: default, name: api-1, uid: \"1\"}
+          - ip: 172.0.1.6
+            targetRef: {kind: Pod, namespace: default, name: api-2, uid: \"2\"}
+          - ip: 172.0.1.7
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP";
+
+    // the service of PODS_YML_STR after removing the addresses of pods, as
+    //  rebuilt from what was written
+    async fn pods_removed(client: Arc<super::Client>) -> super::Service {
+        let new = |yml: String| {
+            super::Service::new(
+                yml,
+                super::Threshold::default(),
+                Arc::new(crate::alert::Alert::default()),
+                client.clone(),
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let mut svc = new(PODS_YML_STR.to_owned());
+        for _ in 0..3 {
+            svc.remove_ep(0).await.unwrap();
+            svc.endpoints.rotate_left(1);
+        }
+        new(svc.repr.to_yaml().unwrap())
+    }
+
+    #[tokio::test]
+    async fn prune_gone() {
+        // api-0 was deleted since and api-1 recreated as another pod
+        let mut svc = pods_removed(pods_server().await).await;
+        assert_eq!(svc.endpoints.len(), 4);
+        svc.prune_gone().await.unwrap();
+        let mut removed: Vec<_> = svc
+            .endpoints
+            .iter()
+            .map(|ep| (ep.addr.to_string(), ep.status.clone()))
+            .collect();
+        removed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            removed,
+            vec![
+                ("172.0.1.6:80".to_owned(), super::EndpointStatus::Removed),
+                ("172.0.1.7:80".to_owned(), super::EndpointStatus::Healthy),
+            ]
+        );
+        let recorded = &svc.repr.metadata.annotations[super::state::REMOVED_ANNOTATION];
+        assert!(recorded.contains("172.0.1.6") && !recorded.contains("172.0.1.5"));
+
+        let i = svc
+            .endpoints
+            .iter()
+            .position(|ep| ep.status == super::EndpointStatus::Removed)
+            .unwrap();
+        svc.restore_ep(i).await.unwrap();
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 2);
+        assert!(svc.repr.metadata.annotations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pods_forbidden() {
+        let mut client = api_server(|_, _| ("403 Forbidden", "{}".to_owned())).await;
+        client.dry_run = true;
+        let mut svc = pods_removed(Arc::new(client)).await;
+
+        // pods that can't be got are taken to be there
+        svc.prune_gone().await.unwrap();
+        assert_eq!(svc.endpoints.len(), 4);
+        let i = svc
+            .endpoints
+            .iter()
+            .position(|ep| ep.status == super::EndpointStatus::Removed)
+            .unwrap();
+        svc.restore_ep(i).await.unwrap();
+        assert_eq!(svc.repr.subsets[0].addresses.len(), 2);
+    }
+
+    #[test]
+    fn service_reconfigure() {
+        let mut svc = super::Service::new(
+            String::from(YML_STR),
+            super::Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            Arc::new(crate::alert::Alert::default()),
+            client(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!svc.endpoints[0].down());
+        let alerter = Arc::new(crate::alert::Alert::default());
+        svc.reconfigure(&crate::config::ServiceConfig {
+            threshold: super::Threshold {
+                restore: 1,
+                remove: 2,
+            },
+            alerter: alerter.clone(),
+            ..Default::default()
+        });
+        assert!(Arc::ptr_eq(&svc.alerter, &alerter));
+        // the counter carries over
+        assert!(svc.endpoints[0].down());
+        assert!(svc.endpoints.iter().all(|ep| ep.threshold.restore == 1));
+    }
+
+    #[test]
+    fn service_new_annotations() {
+        let yml_str = YML_STR.replace(
+            "          namespace: default\n",
+            "          namespace: default
+          annotations:
+            ephc.io/remove-threshold: \"1\"
+            ephc.io/timeout-ms: 1s\n",
+        );
+        let svc = super::Service::new(
+            yml_str,
+            super::Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            Arc::new(crate::alert::Alert::default()),
+            client(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(svc.annotations.enabled);
+        assert!(svc
+            .endpoints
+            .iter()
+            .all(|ep| ep.threshold.remove == 1 && ep.threshold.restore == 3));
+        assert!(svc.annotations.timeout.is_none());
+        assert_eq!(svc.annotations.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn service_annotate() {
+        let yml_str = YML_STR.replace(
+            "          namespace: default\n",
+            "          namespace: default
+          annotations:
+            ephc.io/remove-threshold: \"1\"\n",
+        );
+        let cfg = crate::config::ServiceConfig {
+            threshold: super::Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            ..Default::default()
+        };
+        let new = |restore: &str| {
+            let mut svc = super::Service::new(
+                yml_str.clone(),
+                cfg.threshold.clone(),
+                cfg.alerter.clone(),
+                client(),
+            )
+            .unwrap()
+            .unwrap();
+            let svc_annotations = [
+                ("ephc.io/remove-threshold", "2"),
+                ("ephc.io/restore-threshold", restore),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+            svc.annotate(&svc_annotations, &cfg);
+            Arc::new(RwLock::new(svc))
+        };
+        let svc = new("4");
+        {
+            // those of the Endpoints override those of the Service
+            let svc = svc.read().await;
+            assert!(svc
+                .endpoints
+                .iter()
+                .all(|ep| ep.threshold.remove == 1 && ep.threshold.restore == 4));
+        }
+
+        // a change of the Service alone leaves the version as it is
+        let svcs: super::Services = Default::default();
+        super::upsert_svc(&svcs, svc.clone()).await;
+        svc.write().await.endpoints[0].down();
+        super::upsert_svc(&svcs, new("5")).await;
+        let svc = svcs.read().await.values().next().unwrap().clone();
+        let svc = svc.read().await;
+        assert_eq!(svc.annotations.restore, Some(5));
+        assert!(svc.endpoints.iter().all(|ep| ep.threshold.restore == 5));
+        assert_eq!(svc.endpoints[0].counter.down, 1);
+    }
 }
@@ -1,6 +1,6 @@
 use crate::error::Result;
 use log::{error, info, warn};
-use std::{net::SocketAddr, str::FromStr};
+use std::{collections::BTreeMap, net::SocketAddr, str::FromStr};
 
 use super::endpoint::*;
 use super::yaml::*;
@@ -8,6 +8,7 @@ use super::yaml::*;
 #[derive(Debug, Clone)]
 pub(crate) struct Service {
     pub name: String,
+    pub namespace: String,
     pub endpoints: Vec<Endpoint>,
     // update this after updating k8s, if the new version got from k8s changed
     // means this service has been changed from outside and all members need
@@ -15,7 +16,12 @@ pub(crate) struct Service {
     pub our_version: String,
     // yaml representation of the service
     pub repr: ServiceRepr,
+    // used instead of repr with the EndpointSlices backend
+    pub slices: Option<super::Slices>,
+    // of it, as overridden by those of its Endpoints, or EndpointSlices
+    pub annotations: super::Annotations,
     pub alerter: std::sync::Arc<crate::alert::Alert>,
+    pub client: std::sync::Arc<super::Client>,
 }
 
 impl Service {
@@ -24,55 +30,182 @@ impl Service {
         yml_str: String,
         threshold: Threshold,
         alerter: std::sync::Arc<crate::alert::Alert>,
+        client: std::sync::Arc<super::Client>,
     ) -> Result<Option<Self>> {
         let mut svc_repr = serde_yaml::from_str::<ServiceRepr>(&yml_str)?;
         svc_repr.yaml = yml_str;
-        let subsets: &Vec<SubsetRepr> = &svc_repr.subsets;
+
+        // Addresses we removed before a restart, or before the object was
+        //  changed from outside, are put back into the original and come
+        //  back as removed endpoints to be probed and restored
+        let original = super::state::original_addresses(&svc_repr);
+        if original.subsets != svc_repr.subsets {
+            for subset in &original.subsets[svc_repr.subsets.len()..] {
+                svc_repr.subsets.push(SubsetRepr {
+                    ports: subset.ports.clone(),
+                    ..Default::default()
+                });
+            }
+            svc_repr.yaml = original.to_yaml()?;
+        }
+
+        let annotations = super::Annotations::parse(&svc_repr.metadata.annotations);
+        let threshold = annotations.threshold(threshold);
         let mut eps = Vec::<Endpoint>::new();
-        for subset in subsets {
+        for (k, subset) in original.subsets.iter().enumerate() {
             for port in &subset.ports {
-                if port.protocol == "UDP" {
-                    warn!("we don't support UDP for now");
-                    continue;
-                }
-
                 for addr in &subset.addresses {
+                    let status = if svc_repr.subsets[k]
+                        .addresses
+                        .iter()
+                        .any(|a| a.ip == addr.ip)
+                    {
+                        EndpointStatus::Healthy
+                    } else {
+                        EndpointStatus::Removed
+                    };
                     let addr = SocketAddr::from_str(&format!("{}:{}", addr.ip, port.port))?;
                     let ep = Endpoint {
                         addr,
                         protocol: Protocol::from_str(&port.protocol)?,
-                        status: EndpointStatus::Healthy,
+                        port_name: port.name.clone(),
+                        app_protocol: port.app_protocol.clone(),
+                        subset: k,
+                        status,
                         counter: Counter { up: 0, down: 0 },
                         threshold: threshold.clone(),
+                        probe_state: Default::default(),
+                        admin: None,
                     };
                     eps.push(ep);
                 }
             }
         }
-        if eps.len() == 0 {
+        if eps.is_empty() {
             return Ok(None);
         }
 
         Ok(Some(Service {
             name: svc_repr.metadata.name.clone(),
+            namespace: svc_repr.metadata.namespace.clone(),
             endpoints: eps,
             our_version: svc_repr.metadata.resource_version.clone(),
             repr: svc_repr,
+            slices: None,
+            annotations,
             alerter,
+            client,
         }))
     }
 
-    // TODO: Does all eps only contain one subset?
+    // Thresholds and the alerter of cfg, as overridden by the annotations,
+    //  apply from now on. Counters are kept, one already at or over a lower
+    //  threshold acts on the next result of the same kind.
+    pub fn reconfigure(&mut self, cfg: &crate::config::ServiceConfig) {
+        let threshold = self.annotations.threshold(cfg.threshold.clone());
+        for ep in &mut self.endpoints {
+            ep.threshold = threshold.clone();
+        }
+        self.alerter = cfg.alerter.clone();
+    }
+
+    // Annotations of the Service object apply too, under those of its
+    //  Endpoints, or EndpointSlices, which are all new and from_slices see
+    pub fn annotate(
+        &mut self,
+        svc_annotations: &BTreeMap<String, String>,
+        cfg: &crate::config::ServiceConfig,
+    ) {
+        if svc_annotations.is_empty() {
+            return;
+        }
+        let own: Vec<&BTreeMap<String, String>> = match &self.slices {
+            Some(slices) => slices
+                .current
+                .iter()
+                .map(|slice| &slice.metadata.annotations)
+                .collect(),
+            None => vec![&self.repr.metadata.annotations],
+        };
+        self.annotations = super::Annotations::merge(std::iter::once(svc_annotations).chain(own));
+        self.reconfigure(cfg);
+    }
+
+    // Take over what we know of the endpoints of old, the service it replaces
+    //  after a change from outside: overrides of operators, where endpoints
+    //  are in their schedule, and counters of those whose status is the same.
+    //  Probes in flight report to old, so none is in flight here.
+    pub fn carry_over(&mut self, old: &Service) {
+        for ep in &mut self.endpoints {
+            let old_ep = match old
+                .endpoints
+                .iter()
+                .find(|old_ep| old_ep.addr == ep.addr && old_ep.protocol == ep.protocol)
+            {
+                Some(old_ep) => old_ep,
+                None => continue,
+            };
+            ep.admin = old_ep.admin.clone();
+            ep.probe_state = ProbeState {
+                in_flight: false,
+                ..old_ep.probe_state.clone()
+            };
+            if ep.status == old_ep.status {
+                ep.counter = old_ep.counter.clone();
+            }
+        }
+    }
+
+    // key of this service in Services
+    pub fn key(&self) -> String {
+        super::key(&self.namespace, &self.name)
+    }
+
+    // the address with ip in the k-th subset as it was in the original
+    //  object, so that fields like targetRef and nodeName survive removing
+    //  and restoring it
+    fn original_address(&self, k: usize, ip: &str) -> AddressRepr {
+        let original = match ServiceRepr::from_str(&self.repr.yaml) {
+            Ok(repr) => repr,
+            Err(e) => {
+                error!("failed to parse original repr of {}: {}", self.key(), e);
+                ServiceRepr::default()
+            }
+        };
+        original
+            .subsets
+            .into_iter()
+            .nth(k)
+            .and_then(|subset| subset.addresses.into_iter().find(|addr| addr.ip == ip))
+            .unwrap_or_else(|| AddressRepr {
+                ip: ip.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    // Addresses sharing a port set are grouped into one subset, so an IP is
+    //  removed from and restored to the subset its endpoint came from only,
+    //  the same IP in other subsets is probed on its own ports
     pub async fn remove_ep(&mut self, i: usize) -> Result<()> {
-        let ep_addr = self.endpoints[i].addr.clone();
+        let ep_addr = self.endpoints[i].addr;
         let ep_ip = ep_addr.ip();
+        let k = self.endpoints[i].subset;
+        if let Some(slice) = self.unmanaged_slice(k) {
+            warn!(
+                "slice {} is not managed by the endpointslice controller, \
+                won't remove {} from it",
+                slice, ep_addr
+            );
+            // nothing changed in k8s, start counting failures over
+            self.endpoints[i].set_status(EndpointStatus::Healthy);
+            return Ok(());
+        }
         info!("removing ep: {:?}", ep_addr);
-        self.alerter
-            .alert(crate::alert::Msg::EpDown(
-                self.name.clone(),
-                ep_addr.to_string(),
-            ))
-            .await;
+        self.alerter.alert(crate::alert::Msg::EpDown(
+            self.namespace.clone(),
+            self.name.clone(),
+            ep_addr.to_string(),
+        ));
 
         // if there're only one ep, do nothing except mark it
         if self.endpoints.len() <= 1 {
@@ -84,49 +217,53 @@ impl Service {
             return Ok(());
         }
 
+        if self.slices.is_some() {
+            return self.remove_from_slices(k, ep_ip).await;
+        }
+
         // If the last ep is going to be removed, meaning every ep is unhealthy,
         // restore all original eps in k8s for quicker restoration
         //
-        if self.repr.subsets[0].addresses.len() == 1 {
-            self.alerter
-                .alert(crate::alert::Msg::AllEpDown(self.name.clone()))
-                .await;
+        let ip = ep_ip.to_string();
+        let n_addrs_left = self
+            .repr
+            .subsets
+            .iter()
+            .enumerate()
+            .flat_map(|(j, subset)| subset.addresses.iter().map(move |addr| (j, addr)))
+            .filter(|(j, addr)| !(*j == k && addr.ip == ip))
+            .count();
+        if n_addrs_left == 0 {
+            self.alerter.alert(crate::alert::Msg::AllEpDown(
+                self.namespace.clone(),
+                self.name.clone(),
+            ));
             info!("all eps marked as removed, restoring all eps in k8s");
             for ep in &mut self.endpoints {
-                if ep.addr.ip() == ep_ip {
+                if ep.subset == k && ep.addr.ip() == ep_ip {
                     ep.set_status(EndpointStatus::Removed);
                 }
             }
-            let original_repr = ServiceRepr::from_str(&self.repr.yaml)?;
-            let new_version = super::apply_svc(&self.name, &original_repr.to_yaml()?)?;
-            self.repr = original_repr;
-            self.our_version = new_version;
-            return Ok(());
+            return self.apply_original().await;
         }
 
-        for subset in &mut self.repr.subsets {
-            subset.addresses.retain(|addr| {
-                let ip = match std::net::IpAddr::from_str(&addr.ip) {
-                    Ok(ip) => ip,
-                    Err(_) => {
-                        error!("failed to parse {}", addr.ip);
-                        return false;
-                    }
-                };
-                if ip == ep_ip {
-                    return false;
+        let mut new_repr = self.repr.clone();
+        new_repr.subsets[k].addresses.retain(|addr| {
+            let ip = match std::net::IpAddr::from_str(&addr.ip) {
+                Ok(ip) => ip,
+                Err(_) => {
+                    error!("failed to parse {}", addr.ip);
+                    return true;
                 }
-                true
-            });
-        }
+            };
+            ip != ep_ip
+        });
 
-        let yml = self.repr.to_yaml()?;
-        let new_version = super::apply_svc(&self.name, &yml)?;
-        self.our_version = new_version;
+        self.write(new_repr).await?;
 
-        // mark all eps with the same IP as removed
+        // mark all eps with the same IP in this subset as removed
         for ep in &mut self.endpoints {
-            if ep.addr.ip() == ep_ip {
+            if ep.subset == k && ep.addr.ip() == ep_ip {
                 ep.set_status(EndpointStatus::Removed);
             }
         }
@@ -135,28 +272,204 @@ impl Service {
         Ok(())
     }
 
-    // TODO: Does all eps only contain one subsets?
+    // Whether the address with ip we removed from the k-th subset still
+    //  belongs to the pod it did. A pod deleted, or one of the same name
+    //  created since, which may have got another IP while a new pod got this
+    //  one, isn't ours to put back. One still in k8s or without a pod
+    //  reference is taken to be current, and so is one whose pod can't be
+    //  got, such as without the permission to get pods.
+    async fn pod_current(&self, k: usize, ip: &str) -> bool {
+        if self.repr.subsets[k].addresses.iter().any(|a| a.ip == ip) {
+            return true;
+        }
+        let address = self.original_address(k, ip);
+        let (namespace, name, uid) = match address.target_ref {
+            Some(ObjectRefRepr {
+                kind: Some(kind),
+                namespace,
+                name: Some(name),
+                uid: Some(uid),
+                ..
+            }) if kind == "Pod" => (
+                namespace.unwrap_or_else(|| self.namespace.clone()),
+                name,
+                uid,
+            ),
+            _ => return true,
+        };
+        match self
+            .client
+            .get::<PodRepr>(&super::client::pod_path(&namespace, &name))
+            .await
+        {
+            Ok(pod) => pod.metadata.uid == uid,
+            Err(e) if e.kube_code() == Some(404) => false,
+            Err(e) => {
+                warn!(
+                    "failed to get pod {}/{} of {}, taking it to be there: {}",
+                    namespace, name, ip, e
+                );
+                true
+            }
+        }
+    }
+
+    // Stop checking the address with ip in the k-th subset and drop it from
+    //  the original, and so from the record of what we removed
+    async fn forget_address(&mut self, k: usize, ip: &str) -> Result<()> {
+        let mut original = ServiceRepr::from_str(&self.repr.yaml)?;
+        if let Some(subset) = original.subsets.get_mut(k) {
+            subset.addresses.retain(|a| a.ip != ip);
+        }
+        self.repr.yaml = original.to_yaml()?;
+        self.endpoints
+            .retain(|ep| !(ep.subset == k && ep.addr.ip().to_string() == ip));
+        self.write(self.repr.clone()).await
+    }
+
+    // forget the addresses we removed whose pods are gone, see pod_current
+    pub async fn prune_gone(&mut self) -> Result<()> {
+        if self.slices.is_some() {
+            return Ok(());
+        }
+        let mut removed: Vec<(usize, String)> = self
+            .endpoints
+            .iter()
+            .filter(|ep| ep.status == EndpointStatus::Removed)
+            .map(|ep| (ep.subset, ep.addr.ip().to_string()))
+            .collect();
+        removed.dedup();
+        for (k, ip) in removed {
+            if !self.pod_current(k, &ip).await {
+                info!(
+                    "pod of {} removed from {} is gone, forgetting it",
+                    ip,
+                    self.key()
+                );
+                self.forget_address(k, &ip).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // put back every address as it was in the original object, so that
+    //  nothing stays removed once ephc is gone
+    pub async fn restore_all(&mut self) -> Result<()> {
+        if self
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == EndpointStatus::Healthy)
+        {
+            return Ok(());
+        }
+        info!("restoring all eps of {}", self.key());
+        self.apply_original().await?;
+        for ep in &mut self.endpoints {
+            ep.set_status(EndpointStatus::Healthy);
+        }
+        Ok(())
+    }
+
+    // write the original addresses back to k8s, leaving endpoints as they are
+    async fn apply_original(&mut self) -> Result<()> {
+        if self.slices.is_some() {
+            return self.apply_original_slices().await;
+        }
+        let original_repr = ServiceRepr::from_str(&self.repr.yaml)?;
+        if super::diff::endpoints(&self.repr, &original_repr).is_empty() {
+            return Ok(());
+        }
+        self.write(original_repr).await
+    }
+
+    // write new_repr to k8s in place of repr, along with the record of the
+    //  addresses of the original it lacks
+    async fn write(&mut self, mut new_repr: ServiceRepr) -> Result<()> {
+        let original_repr = ServiceRepr::from_str(&self.repr.yaml)?;
+        super::state::record_addresses(&original_repr, &mut new_repr)?;
+        // the version the change is made on, the original one is older
+        new_repr.metadata.resource_version = self.repr.metadata.resource_version.clone();
+        let new_version = match super::write_svc(&self.client, &self.repr, &new_repr).await {
+            Ok(new_version) => new_version,
+            Err(e) if e.kube_code() == Some(409) => {
+                info!(
+                    "service {} changed from outside since read, reloading",
+                    self.key()
+                );
+                if let Err(e) = self.reload().await {
+                    error!("failed to reload service {}: {}", self.key(), e);
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        new_repr.metadata.resource_version = new_version.clone();
+        self.repr = new_repr;
+        self.our_version = new_version;
+        Ok(())
+    }
+
+    // Take the Endpoints as they are in k8s in place of repr, carrying over
+    //  what we know as when the service is replaced after a change from
+    //  outside, what failed to be written is left to the next probes
+    async fn reload(&mut self) -> Result<()> {
+        let path = super::client::endpoints_path(&self.namespace, &self.name);
+        let yml_str = self.client.get_yaml(&path).await?;
+        let threshold = self
+            .endpoints
+            .first()
+            .map(|ep| ep.threshold.clone())
+            .unwrap_or_default();
+        let mut svc = match Service::new(
+            yml_str,
+            threshold,
+            self.alerter.clone(),
+            self.client.clone(),
+        )? {
+            Some(svc) => svc,
+            None => return Ok(()),
+        };
+        // those of the Service object are only known to us
+        svc.annotations = self.annotations.clone();
+        svc.carry_over(self);
+        // probes in flight report to this very service
+        for ep in &mut svc.endpoints {
+            ep.probe_state.in_flight = self
+                .endpoints
+                .iter()
+                .any(|old| old.addr == ep.addr && old.probe_state.in_flight);
+        }
+        *self = svc;
+        Ok(())
+    }
+
     pub async fn restore_ep(&mut self, i: usize) -> Result<()> {
-        let ep_addr = &self.endpoints[i].addr;
+        let ep_addr = self.endpoints[i].addr;
+        let k = self.endpoints[i].subset;
+        let ip = ep_addr.ip().to_string();
+        if self.slices.is_none() && !self.pod_current(k, &ip).await {
+            info!("pod of {} is gone, won't restore it", ep_addr);
+            return self.forget_address(k, &ip).await;
+        }
         info!("restoring ep: {:?}", ep_addr);
-        self.alerter
-            .alert(crate::alert::Msg::EpUp(
-                self.name.clone(),
-                ep_addr.to_string(),
-            ))
-            .await;
+        self.alerter.alert(crate::alert::Msg::EpUp(
+            self.namespace.clone(),
+            self.name.clone(),
+            ep_addr.to_string(),
+        ));
         let ep_ip = ep_addr.ip();
 
-        // only restore this IP from k8s when all ports of this IP are up
+        // only restore this IP from k8s when all ports of this IP in this
+        //  subset are up
         let mut n_ip_eps = 0;
         let mut n_ip_eps_healthy = 0;
-        for (k, ep) in self.endpoints.iter().enumerate() {
-            if i == k {
+        for (j, ep) in self.endpoints.iter().enumerate() {
+            if i == j {
                 n_ip_eps += 1;
                 n_ip_eps_healthy += 1;
                 continue;
             }
-            if ep.addr.ip() != ep_ip {
+            if ep.subset != k || ep.addr.ip() != ep_ip {
                 continue;
             }
             n_ip_eps += 1;
@@ -176,6 +489,10 @@ impl Service {
             return Ok(());
         }
 
+        if self.slices.is_some() {
+            return self.restore_to_slices(i, ep_ip).await;
+        }
+
         // An address is marked removed but remains in k8s indicates all
         //  addresses were restored since every one of them are down.
         // When one IP turned healthy again, mark all of eps as healthy and let
@@ -185,9 +502,12 @@ impl Service {
         //  eps still remains in k8s
         //  But they will be removed in the next turn of probe, so this priority
         //  is low
-        if self.repr.subsets[0].addresses.contains(&AddressRepr {
-            ip: ep_ip.to_string(),
-        }) {
+        let ip = ep_ip.to_string();
+        if self.repr.subsets[k]
+            .addresses
+            .iter()
+            .any(|addr| addr.ip == ip)
+        {
             info!(
                 "one of all unhealthy endpoints restored, marking all \
                 endpoints healthy."
@@ -196,15 +516,13 @@ impl Service {
                 ep.set_status(EndpointStatus::Healthy);
             }
             return Ok(());
-        } else {
-            self.repr.subsets[0].addresses.push(AddressRepr {
-                ip: ep_ip.to_string(),
-            });
         }
+        let mut new_repr = self.repr.clone();
+        new_repr.subsets[k]
+            .addresses
+            .push(self.original_address(k, &ip));
 
-        let yml = self.repr.to_yaml()?;
-        let new_version = super::apply_svc(&self.name, &yml)?;
-        self.our_version = new_version;
+        self.write(new_repr).await?;
 
         let ep = &mut self.endpoints[i];
         ep.set_status(EndpointStatus::Healthy);
@@ -0,0 +1,455 @@
+use crate::error::Result;
+use log::info;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use super::endpoint::*;
+use super::yaml::*;
+use super::Service;
+
+pub(crate) const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+const MANAGED_BY_LABEL: &str = "endpointslice.kubernetes.io/managed-by";
+// Slices managed by anything else are probed but never changed, their
+//  managers don't know about us and would fight over them
+const SLICE_CONTROLLER: &str = "endpointslice-controller.k8s.io";
+
+// EndpointSlices of a service
+#[derive(Debug, Clone)]
+pub(crate) struct Slices {
+    // as they are in k8s
+    pub current: Vec<EndpointSliceRepr>,
+    // as they were before we changed anything
+    pub original: Vec<EndpointSliceRepr>,
+}
+
+impl Slices {
+    // there's no single resourceVersion for a set of slices, this changes
+    //  whenever any of them changes
+    fn version(&self) -> String {
+        let mut versions: Vec<String> = self
+            .current
+            .iter()
+            .map(|slice| {
+                format!(
+                    "{}={}",
+                    slice.metadata.name, slice.metadata.resource_version
+                )
+            })
+            .collect();
+        versions.sort();
+        versions.join(",")
+    }
+
+    // record in new which endpoints of the original slice of the same name
+    //  it changed
+    fn record(&self, new: &mut EndpointSliceRepr) -> Result<()> {
+        match self
+            .original
+            .iter()
+            .find(|s| s.metadata.name == new.metadata.name)
+        {
+            Some(original) => super::state::record_endpoints(original, new),
+            None => Ok(()),
+        }
+    }
+
+    // conditions of the endpoint with ip in the original slice
+    fn original_conditions(&self, slice: &str, ip: &str) -> ConditionsRepr {
+        self.original
+            .iter()
+            .filter(|s| s.metadata.name == slice)
+            .flat_map(|s| &s.endpoints)
+            .find(|ep| ep.addresses.iter().any(|addr| addr == ip))
+            .map(|ep| ep.conditions.clone())
+            .unwrap_or_else(|| ConditionsRepr {
+                ready: Some(true),
+                serving: Some(true),
+                ..Default::default()
+            })
+    }
+}
+
+fn is_managed(slice: &EndpointSliceRepr) -> bool {
+    slice
+        .metadata
+        .labels
+        .get(MANAGED_BY_LABEL)
+        .map(String::as_str)
+        == Some(SLICE_CONTROLLER)
+}
+
+fn has_ip(ep: &SliceEndpointRepr, ip: &str) -> bool {
+    ep.addresses.iter().any(|addr| addr == ip)
+}
+
+// key of the service a slice belongs to
+pub(crate) fn svc_key(slice: &EndpointSliceRepr) -> Option<String> {
+    let name = slice.metadata.labels.get(SERVICE_NAME_LABEL)?;
+    Some(super::key(&slice.metadata.namespace, name))
+}
+
+impl Service {
+    // construct a Service from all EndpointSlices of it
+    pub fn from_slices(
+        namespace: &str,
+        name: &str,
+        slices: Vec<EndpointSliceRepr>,
+        threshold: Threshold,
+        alerter: std::sync::Arc<crate::alert::Alert>,
+        client: std::sync::Arc<super::Client>,
+    ) -> Result<Option<Self>> {
+        // endpoints we marked not ready before a restart come back as
+        //  removed ones, see Service::new
+        let original: Vec<EndpointSliceRepr> = slices
+            .iter()
+            .map(super::state::original_endpoints)
+            .collect();
+        let annotations =
+            super::Annotations::merge(slices.iter().map(|slice| &slice.metadata.annotations));
+        let threshold = annotations.threshold(threshold);
+        let mut eps = Vec::<Endpoint>::new();
+        for (k, slice) in original.iter().enumerate() {
+            if slice.address_type == "FQDN" {
+                continue;
+            }
+            for port in &slice.ports {
+                let protocol = port.protocol.as_deref().unwrap_or("TCP");
+                let number = match port.port {
+                    Some(number) => number as u16,
+                    None => continue,
+                };
+
+                // not ready ones are not served anyway
+                for (j, ep) in slice.endpoints.iter().enumerate() {
+                    if !ep.is_ready() {
+                        continue;
+                    }
+                    let status = if slices[k].endpoints[j].is_ready() {
+                        EndpointStatus::Healthy
+                    } else {
+                        EndpointStatus::Removed
+                    };
+                    for addr in &ep.addresses {
+                        let ep = Endpoint {
+                            addr: SocketAddr::new(IpAddr::from_str(addr)?, number),
+                            protocol: Protocol::from_str(protocol)?,
+                            port_name: port.name.clone(),
+                            app_protocol: port.app_protocol.clone(),
+                            subset: k,
+                            status: status.clone(),
+                            counter: Counter { up: 0, down: 0 },
+                            threshold: threshold.clone(),
+                            probe_state: Default::default(),
+                            admin: None,
+                        };
+                        eps.push(ep);
+                    }
+                }
+            }
+        }
+        if eps.is_empty() {
+            return Ok(None);
+        }
+
+        let slices = Slices {
+            current: slices,
+            original,
+        };
+        let mut repr = ServiceRepr::default();
+        repr.metadata.name = name.to_owned();
+        repr.metadata.namespace = namespace.to_owned();
+        Ok(Some(Service {
+            name: name.to_owned(),
+            namespace: namespace.to_owned(),
+            endpoints: eps,
+            our_version: slices.version(),
+            repr,
+            slices: Some(slices),
+            annotations,
+            alerter,
+            client,
+        }))
+    }
+
+    // name of the k-th slice if it's one we don't change, see remove_ep
+    pub(super) fn unmanaged_slice(&self, k: usize) -> Option<&str> {
+        let slice = &self.slices.as_ref()?.current[k];
+        if is_managed(slice) {
+            return None;
+        }
+        Some(&slice.metadata.name)
+    }
+
+    // mark every endpoint with ip in the k-th slice as not ready instead of
+    //  deleting it, the slice being managed by the endpointslice controller
+    pub(super) async fn remove_from_slices(&mut self, k: usize, ep_ip: IpAddr) -> Result<()> {
+        let slices = match self.slices.as_mut() {
+            Some(slices) => slices,
+            None => return Ok(()),
+        };
+        let ip = ep_ip.to_string();
+
+        // If the last ready ep is going to be removed, meaning every ep is
+        //  unhealthy, restore all original eps in k8s for quicker restoration
+        let n_ready_left = slices
+            .current
+            .iter()
+            .enumerate()
+            .flat_map(|(j, slice)| slice.endpoints.iter().map(move |ep| (j, ep)))
+            .filter(|(j, ep)| ep.is_ready() && !(*j == k && has_ip(ep, &ip)))
+            .count();
+        if n_ready_left == 0 {
+            self.alerter.alert(crate::alert::Msg::AllEpDown(
+                self.namespace.clone(),
+                self.name.clone(),
+            ));
+            info!("all eps marked as removed, restoring all eps in k8s");
+            for ep in &mut self.endpoints {
+                if ep.subset == k && ep.addr.ip() == ep_ip {
+                    ep.set_status(EndpointStatus::Removed);
+                }
+            }
+            return self.apply_original_slices().await;
+        }
+
+        let slice = &slices.current[k];
+        if slice
+            .endpoints
+            .iter()
+            .any(|ep| ep.is_ready() && has_ip(ep, &ip))
+        {
+            let mut new = slice.clone();
+            for ep in new.endpoints.iter_mut().filter(|ep| has_ip(ep, &ip)) {
+                ep.conditions.ready = Some(false);
+                ep.conditions.serving = Some(false);
+            }
+            slices.record(&mut new)?;
+            new.metadata.resource_version =
+                super::write_slice(&self.client, &slices.current[k], &new).await?;
+            slices.current[k] = new;
+        }
+        self.our_version = slices.version();
+
+        // mark all eps with the same IP in this slice as removed
+        for ep in &mut self.endpoints {
+            if ep.subset == k && ep.addr.ip() == ep_ip {
+                ep.set_status(EndpointStatus::Removed);
+            }
+        }
+
+        info!("ep {} removed, new version: {:?}", ep_ip, self.our_version);
+        Ok(())
+    }
+
+    // write the original endpoints of every slice back to k8s
+    pub(super) async fn apply_original_slices(&mut self) -> Result<()> {
+        let slices = match self.slices.as_mut() {
+            Some(slices) => slices,
+            None => return Ok(()),
+        };
+        for j in 0..slices.current.len() {
+            let original = slices
+                .original
+                .iter()
+                .find(|s| s.metadata.name == slices.current[j].metadata.name);
+            let mut new = slices.current[j].clone();
+            match original {
+                Some(original) if original.endpoints != new.endpoints => {
+                    new.endpoints = original.endpoints.clone();
+                }
+                _ => continue,
+            }
+            slices.record(&mut new)?;
+            new.metadata.resource_version =
+                super::write_slice(&self.client, &slices.current[j], &new).await?;
+            slices.current[j] = new;
+        }
+        self.our_version = slices.version();
+        Ok(())
+    }
+
+    // put back the original conditions of every endpoint with ip in the
+    //  slice the i-th endpoint came from
+    pub(super) async fn restore_to_slices(&mut self, i: usize, ep_ip: IpAddr) -> Result<()> {
+        let k = self.endpoints[i].subset;
+        let slices = match self.slices.as_mut() {
+            Some(slices) => slices,
+            None => return Ok(()),
+        };
+        let ip = ep_ip.to_string();
+        let slice = &slices.current[k];
+
+        // A removed address still ready in a slice we manage indicates all
+        //  addresses were restored since every one of them are down, see
+        //  restore_ep, those marked not ready since are left removed
+        if is_managed(slice)
+            && slice
+                .endpoints
+                .iter()
+                .any(|ep| ep.is_ready() && has_ip(ep, &ip))
+        {
+            info!(
+                "one of all unhealthy endpoints restored, marking all \
+                ready endpoints healthy."
+            );
+            for ep in &mut self.endpoints {
+                let ip = ep.addr.ip().to_string();
+                if slices.current[ep.subset]
+                    .endpoints
+                    .iter()
+                    .any(|slice_ep| slice_ep.is_ready() && has_ip(slice_ep, &ip))
+                {
+                    ep.set_status(EndpointStatus::Healthy);
+                }
+            }
+            return Ok(());
+        }
+
+        if is_managed(slice) {
+            let conditions = slices.original_conditions(&slice.metadata.name, &ip);
+            let mut new = slice.clone();
+            for ep in new.endpoints.iter_mut().filter(|ep| has_ip(ep, &ip)) {
+                ep.conditions = conditions.clone();
+            }
+            slices.record(&mut new)?;
+            new.metadata.resource_version = super::write_slice(&self.client, slice, &new).await?;
+            slices.current[k] = new;
+        }
+        self.our_version = slices.version();
+
+        let ep = &mut self.endpoints[i];
+        ep.set_status(EndpointStatus::Healthy);
+
+        info!("ep {} restored, new version: {:?}", ep_ip, self.our_version);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn service_from_slices() {
+        let slices = serde_json::from_str::<Vec<EndpointSliceRepr>>(
+            r#"[{
+                "metadata": {
+                    "name": "api-abc",
+                    "namespace": "default",
+                    "resourceVersion": "2",
+                    "labels": {
+                        "kubernetes.io/service-name": "api",
+                        "endpointslice.kubernetes.io/managed-by": "endpointslice-controller.k8s.io"
+                    }
+                },
+                "addressType": "IPv4",
+                "endpoints": [
+                    {"addresses": ["172.0.1.4"], "conditions": {"ready": true}},
+                    {"addresses": ["172.0.1.5"], "conditions": {"ready": false}},
+                    {"addresses": ["172.0.1.6"]}
+                ],
+                "ports": [{"name": "http", "port": 80, "protocol": "TCP"}]
+            }, {
+                "metadata": {"name": "api-def", "namespace": "default", "resourceVersion": "1"},
+                "addressType": "IPv4",
+                "endpoints": null,
+                "ports": null
+            }]"#,
+        )
+        .unwrap();
+        assert_eq!(svc_key(&slices[0]).as_deref(), Some("default/api"));
+        assert!(is_managed(&slices[0]));
+        assert!(!is_managed(&slices[1]));
+
+        let svc = Service::from_slices(
+            "default",
+            "api",
+            slices,
+            Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            Arc::new(crate::alert::Alert::default()),
+            crate::kube::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        let addrs: Vec<String> = svc.endpoints.iter().map(|ep| ep.addr.to_string()).collect();
+        assert_eq!(addrs, vec!["172.0.1.4:80", "172.0.1.6:80"]);
+        assert_eq!(svc.our_version, "api-abc=2,api-def=1");
+    }
+
+    fn slice(name: &str, managed: bool, ips: &[&str]) -> EndpointSliceRepr {
+        let mut slice: EndpointSliceRepr = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": name,
+                "namespace": "default",
+                "resourceVersion": "1",
+                "labels": {"kubernetes.io/service-name": "api"}
+            },
+            "addressType": "IPv4",
+            "endpoints": ips
+                .iter()
+                .map(|ip| serde_json::json!({"addresses": [ip], "conditions": {"ready": true}}))
+                .collect::<Vec<_>>(),
+            "ports": [{"name": "http", "port": 80, "protocol": "TCP"}]
+        }))
+        .unwrap();
+        if managed {
+            slice
+                .metadata
+                .labels
+                .insert(MANAGED_BY_LABEL.to_owned(), SLICE_CONTROLLER.to_owned());
+        }
+        slice
+    }
+
+    fn service(slices: Vec<EndpointSliceRepr>) -> Service {
+        Service::from_slices(
+            "default",
+            "api",
+            slices,
+            Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            crate::kube::tests::dry_run_client(),
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unmanaged_slice_untouched() {
+        let mut svc = service(vec![
+            slice("api-abc", true, &["172.0.1.4"]),
+            slice("api-ext", false, &["172.0.1.6"]),
+        ]);
+        svc.remove_ep(1).await.unwrap();
+        assert_eq!(svc.endpoints[1].status, EndpointStatus::Healthy);
+        assert!(svc.slices.as_ref().unwrap().current[1].endpoints[0].is_ready());
+    }
+
+    #[tokio::test]
+    async fn restore_ready_only() {
+        let mut svc = service(vec![
+            slice("api-abc", true, &["172.0.1.4"]),
+            slice("api-def", true, &["172.0.1.5"]),
+        ]);
+        svc.remove_ep(0).await.unwrap();
+        // the last one left brings back the original slices
+        svc.remove_ep(1).await.unwrap();
+        assert!(svc
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == EndpointStatus::Removed));
+        assert!(svc.slices.as_ref().unwrap().current[0].endpoints[0].is_ready());
+
+        // 172.0.1.4 is down again by the time 172.0.1.5 is up
+        svc.slices.as_mut().unwrap().current[0].endpoints[0]
+            .conditions
+            .ready = Some(false);
+        svc.restore_ep(1).await.unwrap();
+        assert_eq!(svc.endpoints[0].status, EndpointStatus::Removed);
+        assert_eq!(svc.endpoints[1].status, EndpointStatus::Healthy);
+    }
+}
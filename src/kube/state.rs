@@ -0,0 +1,258 @@
+use crate::error::Result;
+use log::error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::yaml::*;
+
+// What ephc has taken out of an object is recorded in this annotation of it,
+//  as JSON, so that it's still known to be ours to put back after a restart.
+pub(crate) const REMOVED_ANNOTATION: &str = "ephc.io/removed";
+
+// an address removed from a subset of Endpoints, as it was, including the
+//  targetRef to the pod, whose uid tells whether the IP still belongs to it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RemovedAddress {
+    // ports of the subset it was in, indices of subsets don't survive the
+    //  endpoints controller rewriting the object
+    pub ports: Vec<PortRepr>,
+    pub address: AddressRepr,
+}
+
+// an endpoint of an EndpointSlice marked not ready, with the conditions it
+//  had before
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RemovedEndpoint {
+    pub addresses: Vec<String>,
+    pub conditions: ConditionsRepr,
+}
+
+fn record<T: Serialize>(annotations: &mut BTreeMap<String, String>, removed: &[T]) -> Result<()> {
+    if removed.is_empty() {
+        annotations.remove(REMOVED_ANNOTATION);
+    } else {
+        annotations.insert(
+            REMOVED_ANNOTATION.to_owned(),
+            serde_json::to_string(removed)?,
+        );
+    }
+    Ok(())
+}
+
+fn recorded<T: DeserializeOwned>(metadata: &ServiceMetadataRepr) -> Vec<T> {
+    let value = match metadata.annotations.get(REMOVED_ANNOTATION) {
+        Some(value) => value,
+        None => return vec![],
+    };
+    serde_json::from_str(value).unwrap_or_else(|e| {
+        error!(
+            "ignoring invalid {} of {}/{}: {}",
+            REMOVED_ANNOTATION, metadata.namespace, metadata.name, e
+        );
+        vec![]
+    })
+}
+
+fn same_ports(a: &[PortRepr], b: &[PortRepr]) -> bool {
+    a.len() == b.len() && a.iter().all(|port| b.contains(port))
+}
+
+// record the addresses of original missing in current in the annotations of
+//  current
+pub(crate) fn record_addresses(original: &ServiceRepr, current: &mut ServiceRepr) -> Result<()> {
+    let mut removed = vec![];
+    for (k, subset) in original.subsets.iter().enumerate() {
+        let left = current.subsets.get(k).map_or(&[][..], |s| &s.addresses);
+        for address in &subset.addresses {
+            if !left.iter().any(|a| a.ip == address.ip) {
+                removed.push(RemovedAddress {
+                    ports: subset.ports.clone(),
+                    address: address.clone(),
+                });
+            }
+        }
+    }
+    record(&mut current.metadata.annotations, &removed)
+}
+
+// Endpoints as they were before the addresses recorded in repr were removed.
+// An address which is back, ready or not, was put back by someone else and
+//  is left alone, a subset gone with all its addresses is appended.
+pub(crate) fn original_addresses(repr: &ServiceRepr) -> ServiceRepr {
+    let mut original = repr.clone();
+    for removed in recorded::<RemovedAddress>(&repr.metadata) {
+        let ip = &removed.address.ip;
+        match original
+            .subsets
+            .iter_mut()
+            .find(|s| same_ports(&s.ports, &removed.ports))
+        {
+            Some(subset) => {
+                if !subset
+                    .addresses
+                    .iter()
+                    .chain(&subset.not_ready_addresses)
+                    .any(|a| &a.ip == ip)
+                {
+                    subset.addresses.push(removed.address);
+                }
+            }
+            None => original.subsets.push(SubsetRepr {
+                addresses: vec![removed.address],
+                ports: removed.ports,
+                ..Default::default()
+            }),
+        }
+    }
+    original.metadata.annotations.remove(REMOVED_ANNOTATION);
+    original
+}
+
+// record the original conditions of the endpoints of current changed from
+//  original in the annotations of current
+pub(crate) fn record_endpoints(
+    original: &EndpointSliceRepr,
+    current: &mut EndpointSliceRepr,
+) -> Result<()> {
+    let removed: Vec<RemovedEndpoint> = original
+        .endpoints
+        .iter()
+        .filter(|ep| {
+            current
+                .endpoints
+                .iter()
+                .any(|c| c.addresses == ep.addresses && c.conditions != ep.conditions)
+        })
+        .map(|ep| RemovedEndpoint {
+            addresses: ep.addresses.clone(),
+            conditions: ep.conditions.clone(),
+        })
+        .collect();
+    record(&mut current.metadata.annotations, &removed)
+}
+
+// An EndpointSlice as it was before the endpoints recorded in it were marked
+//  not ready. Only those still not ready are ours, the rest have been
+//  rewritten by the controller since.
+pub(crate) fn original_endpoints(slice: &EndpointSliceRepr) -> EndpointSliceRepr {
+    let mut original = slice.clone();
+    for removed in recorded::<RemovedEndpoint>(&slice.metadata) {
+        for ep in original
+            .endpoints
+            .iter_mut()
+            .filter(|ep| ep.addresses == removed.addresses && !ep.is_ready())
+        {
+            ep.conditions = removed.conditions.clone();
+        }
+    }
+    original.metadata.annotations.remove(REMOVED_ANNOTATION);
+    original
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn addresses() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+            nodeName: node-1
+          - ip: 172.0.1.5
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP
+        - addresses:
+          - ip: 172.0.1.6
+          ports:
+          - name: admin
+            port: 81
+            protocol: TCP";
+        let original = ServiceRepr::from_str(yml_str).unwrap();
+        let mut current = original.clone();
+        current.subsets[0].addresses.remove(0);
+        current.subsets[1].addresses.clear();
+        record_addresses(&original, &mut current).unwrap();
+        assert!(current
+            .metadata
+            .annotations
+            .contains_key(REMOVED_ANNOTATION));
+
+        // the controller dropped the empty subset
+        current.subsets.remove(1);
+        let reconciled = original_addresses(&current);
+        assert_eq!(reconciled.subsets.len(), 2);
+        assert_eq!(
+            reconciled.subsets[0].addresses,
+            vec![
+                original.subsets[0].addresses[1].clone(),
+                original.subsets[0].addresses[0].clone()
+            ]
+        );
+        assert_eq!(reconciled.subsets[1], original.subsets[1]);
+        assert!(reconciled.metadata.annotations.is_empty());
+
+        // put back by the controller
+        current.subsets[0]
+            .not_ready_addresses
+            .push(original.subsets[0].addresses[0].clone());
+        assert_eq!(original_addresses(&current).subsets[0].addresses.len(), 1);
+
+        let mut restored = original.clone();
+        record_addresses(&original, &mut restored).unwrap();
+        assert!(restored.metadata.annotations.is_empty());
+    }
+
+    #[test]
+    fn endpoints() {
+        let original: EndpointSliceRepr = serde_yaml::from_str(
+            "
+        metadata:
+          name: api-abcde
+          namespace: default
+        addressType: IPv4
+        endpoints:
+        - addresses:
+          - 172.0.1.4
+          conditions:
+            ready: true
+            serving: true
+        - addresses:
+          - 172.0.1.5
+          conditions:
+            ready: true
+        ports:
+        - port: 80",
+        )
+        .unwrap();
+        let mut current = original.clone();
+        current.endpoints[0].conditions.ready = Some(false);
+        current.endpoints[0].conditions.serving = Some(false);
+        record_endpoints(&original, &mut current).unwrap();
+        assert_eq!(
+            recorded::<RemovedEndpoint>(&current.metadata),
+            vec![RemovedEndpoint {
+                addresses: vec!["172.0.1.4".to_owned()],
+                conditions: original.endpoints[0].conditions.clone(),
+            }]
+        );
+
+        let reconciled = original_endpoints(&current);
+        assert_eq!(reconciled.endpoints, original.endpoints);
+
+        // made ready again by the controller
+        current.endpoints[0].conditions.ready = Some(true);
+        current.endpoints[0].conditions.serving = None;
+        assert_eq!(original_endpoints(&current).endpoints, current.endpoints);
+    }
+}
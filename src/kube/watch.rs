@@ -0,0 +1,687 @@
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::time::{sleep, Duration};
+
+use super::client::{self, Client};
+use super::yaml::{EndpointSliceRepr, ServiceItemRepr};
+use super::{Backend, Selector, Service, Services};
+use crate::config::Config;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const CHANNEL_SIZE: usize = 1024;
+// the resourceVersion we asked for is too old to watch from
+const GONE: u16 = 410;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Service,
+    Endpoints,
+    EndpointSlice,
+}
+
+impl Kind {
+    fn path(&self, namespace: &str) -> String {
+        match self {
+            Kind::Service => client::services_path(namespace),
+            Kind::Endpoints => client::endpoints_list_path(namespace),
+            Kind::EndpointSlice => client::endpointslices_path(namespace),
+        }
+    }
+
+    fn api_version(&self) -> &'static str {
+        match self {
+            Kind::EndpointSlice => "discovery.k8s.io/v1",
+            _ => "v1",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Service => "Service",
+            Kind::Endpoints => "Endpoints",
+            Kind::EndpointSlice => "EndpointSlice",
+        }
+    }
+}
+
+// what to do after a watch ended
+#[derive(Debug, PartialEq)]
+enum Next {
+    Watch,
+    Relist,
+    Stop,
+}
+
+#[derive(Debug)]
+enum Event {
+    // the full set of objects in a namespace after a (re)list, an empty
+    //  namespace means all of them
+    Restarted(String, Vec<Value>),
+    Applied(Value),
+    Deleted(Value),
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEventRepr {
+    #[serde(rename = "type")]
+    type_: String,
+    object: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListRepr {
+    metadata: ListMetadataRepr,
+    items: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListMetadataRepr {
+    resource_version: String,
+}
+
+// namespace/name of an object
+fn key_of(obj: &Value) -> Option<String> {
+    let name = obj.pointer("/metadata/name").and_then(Value::as_str)?;
+    let ns = obj.pointer("/metadata/namespace").and_then(Value::as_str)?;
+    Some(super::key(ns, name))
+}
+
+fn resource_version_of(obj: &Value) -> Option<&str> {
+    obj.pointer("/metadata/resourceVersion")
+        .and_then(Value::as_str)
+}
+
+// Keep services up to date by watching Services and their Endpoints or
+//  EndpointSlices instead of re-listing everything every refresh interval.
+//  Returns only if all watchers are gone.
+pub(crate) async fn watch(
+    client: Arc<Client>,
+    selector: Selector,
+    mut config: watch::Receiver<Arc<Config>>,
+    svcs: Services,
+) {
+    let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE);
+    let eps_kind = match selector.backend {
+        Backend::Endpoints => Kind::Endpoints,
+        Backend::EndpointSlices => Kind::EndpointSlice,
+    };
+    for ns in selector.namespaces.scopes() {
+        for kind in &[Kind::Service, eps_kind] {
+            tokio::spawn(reflect(client.clone(), *kind, ns.clone(), tx.clone()));
+        }
+    }
+    drop(tx);
+
+    let current = config.borrow_and_update().clone();
+    let mut informer = Informer {
+        client,
+        selector: current.selector(&selector),
+        base: selector,
+        config: current,
+        svcs,
+        services: HashMap::new(),
+        endpoints: HashMap::new(),
+        slices: HashMap::new(),
+    };
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Some((kind, event)) => informer.handle(kind, event).await,
+                None => return,
+            },
+            Ok(()) = config.changed() => {
+                let current = config.borrow_and_update().clone();
+                informer.reconfigure(current).await;
+            }
+        }
+    }
+}
+
+// list then watch objects of kind in namespace forever, relisting whenever
+//  the watch can't be resumed
+async fn reflect(
+    client: Arc<Client>,
+    kind: Kind,
+    namespace: String,
+    tx: mpsc::Sender<(Kind, Event)>,
+) {
+    let path = kind.path(&namespace);
+    loop {
+        let list = match client.list::<ListRepr>(&path).await {
+            Ok(list) => list,
+            Err(e) => {
+                error!("failed to list {}: {}", path, e);
+                sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let items = list
+            .items
+            .into_iter()
+            .map(|mut item| {
+                // items of a list don't carry their own kind
+                if let Value::Object(m) = &mut item {
+                    m.insert("apiVersion".to_owned(), Value::from(kind.api_version()));
+                    m.insert("kind".to_owned(), Value::from(kind.as_str()));
+                }
+                item
+            })
+            .collect();
+        let event = Event::Restarted(namespace.clone(), items);
+        if tx.send((kind, event)).await.is_err() {
+            return;
+        }
+
+        let mut rv = list.metadata.resource_version;
+        loop {
+            match watch_once(&client, kind, &path, &mut rv, &tx).await {
+                Next::Watch => continue,
+                Next::Relist => break,
+                Next::Stop => return,
+            }
+        }
+        info!("relisting {}", path);
+    }
+}
+
+// watch until the stream ends, rv is kept at the last version seen
+async fn watch_once(
+    client: &Client,
+    kind: Kind,
+    path: &str,
+    rv: &mut String,
+    tx: &mpsc::Sender<(Kind, Event)>,
+) -> Next {
+    let mut resp = match client.watch(path, rv).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!("failed to watch {}: {}", path, e);
+            if e.kube_code() == Some(GONE) {
+                return Next::Relist;
+            }
+            sleep(RETRY_DELAY).await;
+            return Next::Watch;
+        }
+    };
+
+    let mut buf = Vec::<u8>::new();
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                debug!("watch {} ended", path);
+                return Next::Watch;
+            }
+            Err(e) => {
+                warn!("watch {} interrupted: {}", path, e);
+                return Next::Watch;
+            }
+        };
+        buf.extend_from_slice(&chunk);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            let event = match serde_json::from_slice::<WatchEventRepr>(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("failed to parse watch event of {}: {}", path, e);
+                    continue;
+                }
+            };
+            if let Some(v) = resource_version_of(&event.object) {
+                *rv = v.to_owned();
+            }
+            let event = match event.type_.as_str() {
+                "ADDED" | "MODIFIED" => Event::Applied(event.object),
+                "DELETED" => Event::Deleted(event.object),
+                "BOOKMARK" => continue,
+                "ERROR" => {
+                    // the object is a Status
+                    warn!("watch {} got error: {}", path, event.object);
+                    return Next::Relist;
+                }
+                t => {
+                    warn!("unknown watch event type {}", t);
+                    continue;
+                }
+            };
+            if tx.send((kind, event)).await.is_err() {
+                return Next::Stop;
+            }
+        }
+    }
+}
+
+struct Informer {
+    client: Arc<Client>,
+    // base with the lists of config
+    selector: Selector,
+    // as given on the command line
+    base: Selector,
+    config: Arc<Config>,
+    svcs: Services,
+    // every service in the namespaces watched, checked or not, so that
+    //  a new allow or block list can be applied
+    services: HashMap<String, ServiceItemRepr>,
+    // latest endpoints object of every service, as yaml
+    endpoints: HashMap<String, String>,
+    // latest endpoint slices of every service, by slice name
+    slices: HashMap<String, BTreeMap<String, EndpointSliceRepr>>,
+}
+
+impl Informer {
+    async fn handle(&mut self, kind: Kind, event: Event) {
+        let keys: Vec<String> = match (kind, event) {
+            (Kind::Service, Event::Restarted(ns, items)) => {
+                let mut keys: HashSet<String> = self
+                    .services
+                    .keys()
+                    .filter(|key| in_namespace(key, &ns))
+                    .cloned()
+                    .collect();
+                self.services.retain(|key, _| !in_namespace(key, &ns));
+                for item in items {
+                    match serde_json::from_value::<ServiceItemRepr>(item) {
+                        Ok(svc) => {
+                            let key = super::key(&svc.metadata.namespace, &svc.metadata.name);
+                            self.services.insert(key.clone(), svc);
+                            keys.insert(key);
+                        }
+                        Err(e) => error!("failed to parse service: {}", e),
+                    }
+                }
+                keys.into_iter().collect()
+            }
+            (Kind::Service, Event::Applied(obj)) => {
+                let svc = match serde_json::from_value::<ServiceItemRepr>(obj) {
+                    Ok(svc) => svc,
+                    Err(e) => {
+                        error!("failed to parse service: {}", e);
+                        return;
+                    }
+                };
+                let key = super::key(&svc.metadata.namespace, &svc.metadata.name);
+                self.services.insert(key.clone(), svc);
+                vec![key]
+            }
+            (Kind::Service, Event::Deleted(obj)) => match key_of(&obj) {
+                Some(key) => {
+                    self.services.remove(&key);
+                    vec![key]
+                }
+                None => return,
+            },
+            (Kind::Endpoints, Event::Restarted(ns, items)) => {
+                let mut keys: HashSet<String> = self
+                    .endpoints
+                    .keys()
+                    .filter(|key| in_namespace(key, &ns))
+                    .cloned()
+                    .collect();
+                self.endpoints.retain(|key, _| !in_namespace(key, &ns));
+                for item in items {
+                    if let Some((key, yml)) = to_yaml(&item) {
+                        self.endpoints.insert(key.clone(), yml);
+                        keys.insert(key);
+                    }
+                }
+                keys.into_iter().collect()
+            }
+            (Kind::Endpoints, Event::Applied(obj)) => match to_yaml(&obj) {
+                Some((key, yml)) => {
+                    self.endpoints.insert(key.clone(), yml);
+                    vec![key]
+                }
+                None => return,
+            },
+            (Kind::Endpoints, Event::Deleted(obj)) => match key_of(&obj) {
+                Some(key) => {
+                    self.endpoints.remove(&key);
+                    vec![key]
+                }
+                None => return,
+            },
+            (Kind::EndpointSlice, Event::Restarted(ns, items)) => {
+                let mut keys: HashSet<String> = self
+                    .slices
+                    .keys()
+                    .filter(|key| in_namespace(key, &ns))
+                    .cloned()
+                    .collect();
+                self.slices.retain(|key, _| !in_namespace(key, &ns));
+                for item in items {
+                    if let Some(key) = self.apply_slice(item) {
+                        keys.insert(key);
+                    }
+                }
+                keys.into_iter().collect()
+            }
+            (Kind::EndpointSlice, Event::Applied(obj)) => match self.apply_slice(obj) {
+                Some(key) => vec![key],
+                None => return,
+            },
+            (Kind::EndpointSlice, Event::Deleted(obj)) => {
+                let slice = match serde_json::from_value::<EndpointSliceRepr>(obj) {
+                    Ok(slice) => slice,
+                    Err(e) => {
+                        error!("failed to parse endpoint slice: {}", e);
+                        return;
+                    }
+                };
+                let key = match super::slice::svc_key(&slice) {
+                    Some(key) => key,
+                    None => return,
+                };
+                if let Some(slices) = self.slices.get_mut(&key) {
+                    slices.remove(&slice.metadata.name);
+                    if slices.is_empty() {
+                        self.slices.remove(&key);
+                    }
+                }
+                vec![key]
+            }
+        };
+        for key in keys {
+            self.sync(&key).await;
+        }
+    }
+
+    // store a slice, returns the key of its service
+    fn apply_slice(&mut self, obj: Value) -> Option<String> {
+        let slice = match serde_json::from_value::<EndpointSliceRepr>(obj) {
+            Ok(slice) => slice,
+            Err(e) => {
+                error!("failed to parse endpoint slice: {}", e);
+                return None;
+            }
+        };
+        // slices not owned by a service are none of our business
+        let key = super::slice::svc_key(&slice)?;
+        self.slices
+            .entry(key.clone())
+            .or_default()
+            .insert(slice.metadata.name.clone(), slice);
+        Some(key)
+    }
+
+    // whether the service of key should be checked
+    fn checked(&self, key: &str) -> bool {
+        self.services
+            .get(key)
+            .is_some_and(|svc| super::should_check(svc, &self.selector.allow, &self.selector.block))
+    }
+
+    // Apply a reloaded config. Services no longer selected are released,
+    //  newly selected ones picked up.
+    async fn reconfigure(&mut self, config: Arc<Config>) {
+        let was_checked: HashSet<String> = self
+            .services
+            .keys()
+            .filter(|key| self.checked(key))
+            .cloned()
+            .collect();
+        self.selector = config.selector(&self.base);
+        self.config = config;
+        let keys: Vec<String> = self.services.keys().cloned().collect();
+        for key in keys {
+            match (was_checked.contains(&key), self.checked(&key)) {
+                (true, false) => super::release_svc(&self.svcs, &key).await,
+                (false, true) => self.sync(&key).await,
+                _ => (),
+            }
+        }
+    }
+
+    // bring the service in svcs in line with what we know from k8s
+    async fn sync(&self, key: &str) {
+        let (ns, name) = key.split_once('/').unwrap_or_default();
+        let cfg = self.config.service(ns, name);
+        let res = match self.selector.backend {
+            _ if !self.checked(key) => Ok(None),
+            Backend::Endpoints => match self.endpoints.get(key) {
+                Some(yml) => Service::new(
+                    yml.clone(),
+                    cfg.threshold.clone(),
+                    cfg.alerter.clone(),
+                    self.client.clone(),
+                ),
+                None => Ok(None),
+            },
+            Backend::EndpointSlices => match self.slices.get(key) {
+                Some(slices) => Service::from_slices(
+                    ns,
+                    name,
+                    slices.values().cloned().collect(),
+                    cfg.threshold.clone(),
+                    cfg.alerter.clone(),
+                    self.client.clone(),
+                ),
+                _ => Ok(None),
+            },
+        };
+        let mut svc = match res {
+            Ok(Some(svc)) => svc,
+            Ok(None) => {
+                super::release_svc(&self.svcs, key).await;
+                return;
+            }
+            Err(e) => {
+                error!("failed to build service {}: {}", key, e);
+                return;
+            }
+        };
+        if let Some(item) = self.services.get(key) {
+            svc.annotate(&item.metadata.annotations, &cfg);
+        }
+        super::upsert_svc(&self.svcs, Arc::new(RwLock::new(svc))).await;
+    }
+}
+
+fn in_namespace(key: &str, namespace: &str) -> bool {
+    namespace.is_empty() || key.starts_with(&format!("{}/", namespace))
+}
+
+fn to_yaml(obj: &Value) -> Option<(String, String)> {
+    let key = key_of(obj)?;
+    match serde_yaml::to_string(obj) {
+        Ok(yml) => Some((key, yml)),
+        Err(e) => {
+            error!("failed to convert endpoints {} to yaml: {}", key, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn informer() -> Informer {
+        let selector = Selector {
+            namespaces: crate::kube::Namespaces::All,
+            allow: None,
+            block: None,
+            backend: Backend::Endpoints,
+        };
+        Informer {
+            client: crate::kube::tests::client(),
+            selector: selector.clone(),
+            base: selector,
+            config: Arc::new(Config::new(crate::config::ServiceConfig {
+                threshold: crate::kube::Threshold {
+                    restore: 3,
+                    remove: 3,
+                },
+                ..Default::default()
+            })),
+            svcs: Arc::new(RwLock::new(HashMap::new())),
+            services: HashMap::new(),
+            endpoints: HashMap::new(),
+            slices: HashMap::new(),
+        }
+    }
+
+    fn endpoints(ns: &str, name: &str, rv: &str) -> Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Endpoints",
+            "metadata": {"name": name, "namespace": ns, "resourceVersion": rv},
+            "subsets": [{
+                "addresses": [{"ip": "172.0.1.4"}, {"ip": "172.0.1.5"}],
+                "ports": [{"port": 80, "protocol": "TCP"}]
+            }]
+        })
+    }
+
+    fn service(ns: &str, name: &str, type_: &str) -> Value {
+        serde_json::json!({
+            "metadata": {"name": name, "namespace": ns},
+            "spec": {"type": type_}
+        })
+    }
+
+    async fn keys(svcs: &Services) -> Vec<String> {
+        let mut keys: Vec<String> = svcs.read().await.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn informer_follows_events() {
+        let mut informer = informer();
+        let svcs = informer.svcs.clone();
+
+        informer
+            .handle(
+                Kind::Endpoints,
+                Event::Restarted(
+                    "".to_owned(),
+                    vec![
+                        endpoints("default", "api", "1"),
+                        endpoints("default", "ext", "1"),
+                        endpoints("prod", "api", "1"),
+                    ],
+                ),
+            )
+            .await;
+        assert!(svcs.read().await.is_empty());
+
+        informer
+            .handle(
+                Kind::Service,
+                Event::Restarted(
+                    "".to_owned(),
+                    vec![
+                        service("default", "api", "ClusterIP"),
+                        service("default", "ext", "ExternalName"),
+                        service("prod", "api", "ClusterIP"),
+                    ],
+                ),
+            )
+            .await;
+        assert_eq!(keys(&svcs).await, vec!["default/api", "prod/api"]);
+
+        informer
+            .handle(
+                Kind::Endpoints,
+                Event::Applied(endpoints("prod", "api", "2")),
+            )
+            .await;
+        let version = svcs.read().await["prod/api"]
+            .read()
+            .await
+            .our_version
+            .clone();
+        assert_eq!(version, "2");
+
+        informer
+            .handle(
+                Kind::Service,
+                Event::Deleted(service("default", "api", "ClusterIP")),
+            )
+            .await;
+        assert_eq!(keys(&svcs).await, vec!["prod/api"]);
+
+        // a relist of one namespace leaves the others alone
+        informer
+            .handle(
+                Kind::Service,
+                Event::Restarted("default".to_owned(), vec![]),
+            )
+            .await;
+        assert_eq!(keys(&svcs).await, vec!["prod/api"]);
+        informer
+            .handle(Kind::Service, Event::Restarted("prod".to_owned(), vec![]))
+            .await;
+        assert!(svcs.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn informer_releases() {
+        let mut informer = Informer {
+            client: crate::kube::tests::dry_run_client(),
+            ..informer()
+        };
+        let svcs = informer.svcs.clone();
+        informer
+            .handle(
+                Kind::Endpoints,
+                Event::Applied(endpoints("default", "api", "1")),
+            )
+            .await;
+        informer
+            .handle(
+                Kind::Service,
+                Event::Applied(service("default", "api", "ClusterIP")),
+            )
+            .await;
+        let svc = svcs.read().await["default/api"].clone();
+        svc.write().await.remove_ep(0).await.unwrap();
+
+        // what was removed is put back once it's no longer checked
+        informer
+            .handle(
+                Kind::Service,
+                Event::Deleted(service("default", "api", "ClusterIP")),
+            )
+            .await;
+        assert!(svcs.read().await.is_empty());
+        assert!(svc
+            .read()
+            .await
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == crate::kube::EndpointStatus::Healthy));
+    }
+
+    #[tokio::test]
+    async fn informer_reconfigures() {
+        let mut informer = informer();
+        let svcs = informer.svcs.clone();
+        for ns in &["default", "prod"] {
+            informer
+                .handle(Kind::Endpoints, Event::Applied(endpoints(ns, "api", "1")))
+                .await;
+            informer
+                .handle(
+                    Kind::Service,
+                    Event::Applied(service(ns, "api", "ClusterIP")),
+                )
+                .await;
+        }
+        assert_eq!(keys(&svcs).await, vec!["default/api", "prod/api"]);
+
+        let config = informer.config.clone();
+        informer.base.block = Some(vec!["prod/api".to_owned()]);
+        informer.reconfigure(config.clone()).await;
+        assert_eq!(keys(&svcs).await, vec!["default/api"]);
+
+        informer.base.block = None;
+        informer.reconfigure(config).await;
+        assert_eq!(keys(&svcs).await, vec!["default/api", "prod/api"]);
+    }
+}
@@ -1,45 +1,223 @@
 use crate::error::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+// Every repr keeps the fields it doesn't know about in `extra`, so writing
+//  an object back only changes what we meant to change.
+pub type Extra = BTreeMap<String, serde_yaml::Value>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectRefRepr {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct AddressRepr {
     pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_ref: Option<ObjectRefRepr>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct PortRepr {
     #[serde(skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
+    pub name: Option<String>,
     pub port: u32,
     pub protocol: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_protocol: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct SubsetRepr {
+    // missing when none of the addresses is ready
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub addresses: Vec<AddressRepr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_ready_addresses: Vec<AddressRepr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ports: Vec<PortRepr>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceMetadataRepr {
     pub name: String,
-    #[serde(skip_serializing)]
+    #[serde(default)]
+    pub namespace: String,
+    #[serde(default)]
     pub resource_version: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub extra: Extra,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub(crate) struct ServiceRepr {
     #[serde(rename = "apiVersion")]
     api_version: String,
     kind: String,
     pub metadata: ServiceMetadataRepr,
+    // missing when the service has no endpoints at all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub subsets: Vec<SubsetRepr>,
+    #[serde(flatten)]
+    pub extra: Extra,
     #[serde(skip)]
     pub yaml: String,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConditionsRepr {
+    // unknown state should be interpreted as ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ready: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serving: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terminating: Option<bool>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceEndpointRepr {
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub conditions: ConditionsRepr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_ref: Option<ObjectRefRepr>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl SliceEndpointRepr {
+    pub fn is_ready(&self) -> bool {
+        self.conditions.ready != Some(false)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SlicePortRepr {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // unset means all ports, which we can't probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_protocol: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+fn null_as_empty<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+// discovery.k8s.io/v1 EndpointSlice
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EndpointSliceRepr {
+    // not set on items of a list
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub api_version: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub kind: String,
+    pub metadata: ServiceMetadataRepr,
+    pub address_type: String,
+    // null when there's no endpoint
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub endpoints: Vec<SliceEndpointRepr>,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub ports: Vec<SlicePortRepr>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct EndpointSliceListRepr {
+    pub items: Vec<EndpointSliceRepr>,
+}
+
+// only the fields of a v1 ServiceList needed to pick services to check
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServiceListRepr {
+    pub items: Vec<ServiceItemRepr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServiceItemRepr {
+    pub metadata: ServiceItemMetadataRepr,
+    #[serde(default)]
+    pub spec: ServiceSpecRepr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServiceItemMetadataRepr {
+    pub name: String,
+    #[serde(default)]
+    pub namespace: String,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+// only what tells one pod from another of the same name
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PodRepr {
+    pub metadata: PodMetadataRepr,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PodMetadataRepr {
+    pub uid: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ServiceSpecRepr {
+    #[serde(rename = "type", default)]
+    pub type_: String,
+}
+
 impl FromStr for ServiceRepr {
     type Err = serde_yaml::Error;
 
@@ -72,27 +250,80 @@ mod test {
             kind: "Endpoints".to_owned(),
             metadata: ServiceMetadataRepr {
                 name: "test".to_owned(),
+                namespace: "default".to_owned(),
                 resource_version: "1".to_owned(),
+                ..Default::default()
             },
             subsets: vec![SubsetRepr {
                 addresses: vec![AddressRepr {
                     ip: "1.1.1.1".to_owned(),
+                    ..Default::default()
                 }],
                 ports: vec![
                     PortRepr {
                         name: Some("23".to_owned()),
                         port: 23,
                         protocol: "UDP".to_owned(),
+                        ..Default::default()
                     },
                     PortRepr {
                         name: None,
                         port: 80,
                         protocol: "TCP".to_owned(),
+                        ..Default::default()
                     },
                 ],
+                ..Default::default()
             }],
             yaml: "s".to_owned(),
+            ..Default::default()
         };
         println!("{:?}", s.to_yaml().unwrap());
     }
+
+    #[test]
+    fn round_trip() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          annotations:
+            endpoints.kubernetes.io/last-change-trigger-time: \"2021-06-01T08:00:00Z\"
+          labels:
+            app: ephc-test
+          name: ephc-test
+          namespace: default
+          resourceVersion: \"82479279\"
+          uid: 0ec10531-4ae1-11e9-9c9c-f86eee307061
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+            nodeName: node-1
+            targetRef:
+              kind: Pod
+              name: ephc-test-6d4cf56db6-x2x9v
+              namespace: default
+              resourceVersion: \"82479270\"
+              uid: 6a5b6c2e-4ae1-11e9-9c9c-f86eee307061
+          - ip: 172.0.1.5
+            hostname: ephc-test-1
+          notReadyAddresses:
+          - ip: 172.0.1.6
+            nodeName: node-2
+          ports:
+          - name: http
+            port: 31000
+            protocol: TCP
+            appProtocol: http
+        - notReadyAddresses:
+          - ip: 172.0.1.7
+          ports:
+          - port: 53
+            protocol: UDP";
+
+        let repr = ServiceRepr::from_str(yml_str).unwrap();
+        let original = serde_yaml::from_str::<serde_yaml::Value>(yml_str).unwrap();
+        let written = serde_yaml::from_str::<serde_yaml::Value>(&repr.to_yaml().unwrap()).unwrap();
+        assert_eq!(original, written);
+    }
 }
@@ -1,8 +1,13 @@
 use lazy_static::lazy_static;
-use log::{debug, error, info};
-use std::{collections::HashMap, sync::Arc};
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::{
-    sync::RwLock,
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, watch, RwLock},
     time::{self, Duration},
 };
 
@@ -10,106 +15,156 @@ lazy_static! {
     static ref CFG: cmd::AppOpt = cmd::init();
 }
 
+mod admin;
 mod alert;
 mod cmd;
+mod config;
 mod error;
 mod kube;
+mod metrics;
 mod probe;
+mod server;
+mod status;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let alert = Arc::new(alert::Alert::from_url_scheme(&CFG.alert_channel));
+    if let Some(cmd) = &CFG.admin {
+        cmd.run().await?;
+        return Ok(());
+    }
 
-    let services = Arc::new(RwLock::new(
-        HashMap::<String, Arc<RwLock<kube::Service>>>::new(),
-    ));
+    if let Some(path) = &CFG.check_config {
+        match config::Config::load(base_config()?, Path::new(path)) {
+            Ok(_) => println!("{} is valid", path),
+            Err(e) => {
+                eprintln!("error: {}", e.reason());
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-    let svcs = services.clone();
-    let mut interval = time::interval(Duration::from_secs(CFG.refresh_interval));
-    let opt_clone = CFG.clone();
-    let jh_refresh = tokio::task::spawn(async move {
-        loop {
-            interval.tick().await;
-            info!("refresh service list");
-            let t = kube::Threshold {
-                restore: opt_clone.restore,
-                remove: opt_clone.remove,
-            };
-            let res = match kube::get_svcs(
-                &opt_clone.allow_list,
-                &opt_clone.block_list,
-                t,
-                alert.clone(),
-            ) {
-                Ok(res) => res,
-                Err(e) => {
-                    error!("failed to get services: {}", e);
-                    vec![]
-                }
-            };
-            let mut svcs_writer = svcs.write().await;
-            for svc in res {
-                let svc_clone = svc.clone();
-                let svc_reader = svc_clone.read().await;
-                match svcs_writer.get(&svc_reader.name) {
-                    Some(old) => {
-                        let old = old.clone();
-                        let old_reader = old.read().await;
-                        let version = svc_reader.our_version.parse::<u64>().unwrap_or_else(|e| {
-                            debug!("failed to parse new resourceVersion: {}", e);
-                            0
-                        });
-                        let old_version =
-                            old_reader.our_version.parse::<u64>().unwrap_or_else(|e| {
-                                debug!("failed to parse old resourceVersion: {}", e);
-                                0
-                            });
+    let config_path = CFG.config.as_ref().map(PathBuf::from);
+    let config = match &config_path {
+        Some(path) => config::Config::load(base_config()?, path),
+        None => Ok(config::Config::new(base_config()?)),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("error: {}", e.reason());
+        std::process::exit(1);
+    });
+
+    metrics::init(CFG.cluster_name.as_deref(), CFG.dry_run);
+    let mut client = kube::Client::infer()?;
+    client.dry_run = CFG.dry_run;
+    let client = Arc::new(client);
+
+    let services: kube::Services = Arc::new(RwLock::new(HashMap::new()));
 
-                        if version == old_version {
-                            debug!("service {} not changed", svc_reader.name);
-                            continue;
-                        }
-                        if version < old_version {
-                            error!(
-                                "service {} got version {} under our version {}",
-                                svc_reader.name, version, old_version
-                            );
-                            continue;
-                        }
+    let (config_tx, config) = watch::channel(Arc::new(config));
+    tokio::task::spawn(config::reload(config_path, config_tx, services.clone()));
 
-                        info!(
-                            "service {} changed from outside, replacing",
-                            svc_reader.name
-                        );
-                        debug!(
-                            "new version: {}, our version: {}",
-                            svc_reader.our_version, old_reader.our_version
-                        );
-                        svcs_writer.insert(svc_reader.name.clone(), svc);
-                    }
-                    None => {
-                        svcs_writer.insert(svc_reader.name.clone(), svc);
-                    }
+    let svcs = services.clone();
+    let opt_clone = CFG.clone();
+    let namespaces = if opt_clone.all_namespaces {
+        kube::Namespaces::All
+    } else {
+        let default_namespaces = vec![client.namespace.clone()];
+        kube::Namespaces::Some(opt_clone.namespaces.clone().unwrap_or(default_namespaces))
+    };
+    let selector = kube::Selector {
+        namespaces,
+        allow: opt_clone.allow_list.clone(),
+        block: opt_clone.block_list.clone(),
+        backend: opt_clone.backend,
+    };
+    let jh_refresh = if opt_clone.watch {
+        tokio::task::spawn(kube::watch(client, selector, config.clone(), svcs))
+    } else {
+        let config = config.clone();
+        let mut interval = time::interval(Duration::from_secs(CFG.refresh_interval));
+        tokio::task::spawn(async move {
+            loop {
+                interval.tick().await;
+                info!("refresh service list");
+                let start = std::time::Instant::now();
+                // the config may have been reloaded since
+                let config = config.borrow().clone();
+                let selector = config.selector(&selector);
+                if let Err(e) = kube::refresh(client.clone(), &selector, &config, &svcs).await {
+                    error!("failed to refresh services: {}", e);
                 }
+                metrics::refresh_done(start.elapsed());
             }
-        }
-    });
+        })
+    };
 
     let svcs = services.clone();
-    let probe_interval = CFG.probe_interval;
-    let mut interval = time::interval(Duration::from_millis(probe_interval));
-    let jh_probe = tokio::task::spawn(async move {
-        loop {
-            interval.tick().await;
-            debug!("start probing");
-            let svcs = svcs.clone();
-            probe::probe(svcs, CFG.connection_timeout).await;
-            debug!("finished probing");
-        }
-    });
+    let prober = probe::Prober::new(
+        Arc::new(CFG.probes.clone()),
+        config,
+        CFG.max_probes,
+        CFG.max_service_probes,
+    );
+    let (stop_probe, stop) = oneshot::channel();
+    let jh_probe = tokio::task::spawn(prober.run(svcs, stop));
+
+    for (addr, api) in [
+        (CFG.listen, server::Api::Status),
+        (CFG.admin_listen, server::Api::Admin),
+    ] {
+        let server = server::serve(addr, api, services.clone()).unwrap_or_else(|e| {
+            eprintln!("error: failed to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        tokio::task::spawn(server);
+    }
 
-    jh_refresh.await.unwrap();
-    jh_probe.await.unwrap();
+    shutdown_signal().await?;
+    info!("shutting down, restoring removed endpoints");
+    jh_refresh.abort();
+    let _ = stop_probe.send(());
+    let shutdown = async {
+        jh_probe.await.unwrap();
+        kube::restore_all(&services).await;
+    };
+    if time::timeout(Duration::from_secs(CFG.shutdown_timeout), shutdown)
+        .await
+        .is_err()
+    {
+        error!("shutdown timed out, some endpoints may remain removed");
+    }
+
+    Ok(())
+}
+
+// what the command line sets for every service
+fn base_config() -> error::Result<config::ServiceConfig> {
+    Ok(config::ServiceConfig {
+        threshold: kube::Threshold {
+            restore: CFG.restore,
+            remove: CFG.remove,
+        },
+        schedule: probe::Schedule {
+            interval: Duration::from_millis(CFG.probe_interval),
+            removed_interval: Duration::from_millis(CFG.removed_probe_interval),
+            max_removed_interval: Duration::from_millis(CFG.max_removed_probe_interval),
+            jitter: CFG.probe_jitter,
+        },
+        timeout: CFG.connection_timeout,
+        probe: None,
+        ports: None,
+        alerter: Arc::new(alert::Alert::from_urls(&CFG.alert_channels)?),
+    })
+}
 
+// resolves on the first SIGTERM or SIGINT
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
     Ok(())
 }
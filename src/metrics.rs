@@ -0,0 +1,303 @@
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorKind};
+use crate::kube::{EndpointStatus, Services};
+
+static CLUSTER: OnceLock<String> = OnceLock::new();
+static DRY_RUN: OnceLock<bool> = OnceLock::new();
+
+lazy_static! {
+    static ref PROBE_DURATION: HistogramVec = register_histogram_vec!(
+        "ephc_probe_duration_seconds",
+        "Time taken to probe an endpoint",
+        &["cluster", "namespace", "service", "endpoint"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .unwrap();
+    static ref PROBE_SUCCESSES: IntCounterVec = register_int_counter_vec!(
+        "ephc_probe_successes_total",
+        "Probes which found the endpoint healthy",
+        &["cluster", "namespace", "service"]
+    )
+    .unwrap();
+    static ref PROBE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "ephc_probe_failures_total",
+        "Probes which found the endpoint unhealthy, by reason",
+        &["cluster", "namespace", "service", "reason"]
+    )
+    .unwrap();
+    static ref ENDPOINTS: IntGaugeVec = register_int_gauge_vec!(
+        "ephc_endpoints",
+        "Endpoints being checked, by status, removed ones are only taken to be in dry run",
+        &["cluster", "namespace", "service", "status", "dry_run"]
+    )
+    .unwrap();
+    static ref OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "ephc_endpoint_operations_total",
+        "Endpoints removed from or restored to Kubernetes, or that would have been in dry run",
+        &["cluster", "namespace", "service", "operation", "dry_run"]
+    )
+    .unwrap();
+    static ref APPLY_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "ephc_apply_errors_total",
+        "Failures writing endpoints back to Kubernetes",
+        &["cluster", "namespace", "service"]
+    )
+    .unwrap();
+    static ref REFRESH_DURATION: HistogramVec = register_histogram_vec!(
+        "ephc_refresh_duration_seconds",
+        "Time taken to refresh services from Kubernetes",
+        &["cluster"]
+    )
+    .unwrap();
+}
+
+// name of the cluster every metric is labelled with, and whether operations
+//  are only logged
+pub(crate) fn init(cluster: Option<&str>, dry_run: bool) {
+    let _ = CLUSTER.set(cluster.unwrap_or("unknown").to_owned());
+    let _ = DRY_RUN.set(dry_run);
+}
+
+fn cluster() -> &'static str {
+    CLUSTER.get().map(String::as_str).unwrap_or("unknown")
+}
+
+// label of what would have been done in dry run
+fn dry_run() -> &'static str {
+    match DRY_RUN.get() {
+        Some(true) => "true",
+        _ => "false",
+    }
+}
+
+// Operation on an endpoint in Kubernetes
+pub(crate) enum Operation {
+    Remove,
+    Restore,
+}
+
+pub(crate) fn probe_done(namespace: &str, service: &str, endpoint: &str, took: Duration) {
+    PROBE_DURATION
+        .with_label_values(&[cluster(), namespace, service, endpoint])
+        .observe(took.as_secs_f64());
+}
+
+// drop the series of endpoints no longer checked, so that they don't pile
+//  up as pods come and go
+pub(crate) fn endpoints_gone<'a>(
+    namespace: &str,
+    service: &str,
+    endpoints: impl IntoIterator<Item = &'a SocketAddr>,
+) {
+    for ep in endpoints {
+        // not there if it was never probed
+        let _ =
+            PROBE_DURATION.remove_label_values(&[cluster(), namespace, service, &ep.to_string()]);
+    }
+}
+
+pub(crate) fn probe_result(namespace: &str, service: &str, res: &Result<(), Error>) {
+    match res {
+        Ok(_) => PROBE_SUCCESSES
+            .with_label_values(&[cluster(), namespace, service])
+            .inc(),
+        Err(e) => PROBE_FAILURES
+            .with_label_values(&[cluster(), namespace, service, reason(e)])
+            .inc(),
+    }
+}
+
+pub(crate) fn operation(namespace: &str, service: &str, op: Operation) {
+    let op = match op {
+        Operation::Remove => "remove",
+        Operation::Restore => "restore",
+    };
+    OPERATIONS
+        .with_label_values(&[cluster(), namespace, service, op, dry_run()])
+        .inc();
+}
+
+pub(crate) fn apply_error(namespace: &str, service: &str) {
+    APPLY_ERRORS
+        .with_label_values(&[cluster(), namespace, service])
+        .inc();
+}
+
+pub(crate) fn refresh_done(took: Duration) {
+    REFRESH_DURATION
+        .with_label_values(&[cluster()])
+        .observe(took.as_secs_f64());
+}
+
+// why a probe failed
+fn reason(e: &Error) -> &'static str {
+    if let ErrorKind::Timeout = e.kind() {
+        return "timeout";
+    }
+    match e.io_kind() {
+        Some(std::io::ErrorKind::ConnectionRefused) => return "refused",
+        Some(std::io::ErrorKind::ConnectionReset) => return "reset",
+        Some(std::io::ErrorKind::TimedOut) => return "timeout",
+        _ => {}
+    }
+    // HTTP and gRPC probes fail with errors of reqwest, and of hyper under
+    //  it, which don't always come from an io error
+    if let Some(e) = e.source_of::<hyper::Error>() {
+        if e.is_timeout() {
+            return "timeout";
+        }
+        if e.is_incomplete_message() || e.is_closed() {
+            return "reset";
+        }
+    }
+    if let Some(e) = e.source_of::<reqwest::Error>() {
+        if e.is_timeout() {
+            return "timeout";
+        }
+        if e.is_connect() {
+            return "refused";
+        }
+    }
+    match e.kind() {
+        // reached the endpoint but got the wrong answer
+        ErrorKind::Probe => "unhealthy",
+        _ => "error",
+    }
+}
+
+// all metrics in the text format, with gauges of endpoints taken from svcs
+pub(crate) async fn gather(svcs: &Services) -> Vec<u8> {
+    ENDPOINTS.reset();
+    let svcs: Vec<_> = svcs.read().await.values().cloned().collect();
+    for svc in svcs {
+        let svc = svc.read().await;
+        let (mut healthy, mut removed) = (0, 0);
+        for ep in &svc.endpoints {
+            match ep.status {
+                EndpointStatus::Healthy => healthy += 1,
+                EndpointStatus::Removed => removed += 1,
+            }
+        }
+        for (status, n) in [("healthy", healthy), ("removed", removed)] {
+            ENDPOINTS
+                .with_label_values(&[cluster(), &svc.namespace, &svc.name, status, dry_run()])
+                .set(n);
+        }
+    }
+
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+        error!("failed to encode metrics: {}", e);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+
+    #[test]
+    fn failure_reason() {
+        let refused = Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(reason(&refused), "refused");
+        assert_eq!(reason(&Error::timeout("timed out")), "timeout");
+        assert_eq!(reason(&Error::probe("unexpected status 503")), "unhealthy");
+        assert_eq!(reason(&Error::new("other")), "error");
+    }
+
+    #[tokio::test]
+    async fn http_failure_reason() {
+        use tokio::io::AsyncReadExt;
+        let get = |addr: SocketAddr| async move {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_millis(100))
+                .build()
+                .unwrap();
+            let e = client
+                .get(format!("http://{}/", addr))
+                .send()
+                .await
+                .unwrap_err();
+            Error::from(e)
+        };
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert_eq!(reason(&get(closed).await), "refused");
+
+        // one never answers, the other hangs up on reading the request
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rude = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (silent_addr, rude_addr) = (silent.local_addr().unwrap(), rude.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            loop {
+                conns.push(silent.accept().await.unwrap());
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = rude.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+        assert_eq!(reason(&get(silent_addr).await), "timeout");
+        assert_eq!(reason(&get(rude_addr).await), "reset");
+    }
+
+    #[test]
+    fn endpoints_gone() {
+        let ep: SocketAddr = "172.0.1.4:80".parse().unwrap();
+        let series = || {
+            PROBE_DURATION
+                .collect()
+                .iter()
+                .flat_map(|family| family.get_metric())
+                .filter(|m| m.get_label().iter().any(|l| l.get_value() == "gone"))
+                .count()
+        };
+        probe_done("default", "gone", &ep.to_string(), Duration::from_millis(1));
+        assert_eq!(series(), 1);
+        super::endpoints_gone("default", "gone", [&ep]);
+        assert_eq!(series(), 0);
+    }
+
+    #[tokio::test]
+    async fn gather_endpoints() {
+        let svcs: Services = Default::default();
+        let svc = crate::kube::Service::new(
+            crate::kube::tests::YML_STR.to_owned(),
+            Default::default(),
+            Default::default(),
+            crate::kube::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        svcs.write().await.insert(
+            svc.key(),
+            std::sync::Arc::new(tokio::sync::RwLock::new(svc)),
+        );
+        probe_result("default", "api", &Err(Error::timeout("timed out")));
+        let text = String::from_utf8(gather(&svcs).await).unwrap();
+        assert!(text.contains(
+            r#"ephc_probe_failures_total{cluster="unknown",namespace="default",reason="timeout",service="api"} 1"#
+        ));
+        // removed ones may never have been removed
+        assert!(text.contains(
+            r#"ephc_endpoints{cluster="unknown",dry_run="false",namespace="default",service="ephc-test",status="removed"} 0"#
+        ));
+    }
+}
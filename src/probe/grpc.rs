@@ -0,0 +1,178 @@
+use crate::error::{Error, Result};
+use lazy_static::lazy_static;
+use std::net::SocketAddr;
+
+// grpc.health.v1.HealthCheckResponse.ServingStatus.SERVING
+const SERVING: u64 = 1;
+
+lazy_static! {
+    // gRPC over cleartext HTTP/2, shared by every gRPC probe
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+}
+
+// calls grpc.health.v1.Health/Check, the endpoint is healthy only if it
+//  answers SERVING
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GrpcProbe {
+    // empty for the health of the whole server
+    pub service: String,
+}
+
+impl GrpcProbe {
+    // build from the key=value options of a probe spec
+    pub fn new(opts: &[(&str, Option<&str>)]) -> Result<Self> {
+        let mut service = String::new();
+        for (k, v) in opts {
+            match (*k, *v) {
+                ("service", Some(v)) => service = v.to_owned(),
+                (k, _) => return Err(Error::config(format!("invalid grpc probe option {}", k))),
+            }
+        }
+        Ok(Self { service })
+    }
+
+    pub async fn check(&self, addr: SocketAddr) -> Result<()> {
+        let url = format!("http://{}/grpc.health.v1.Health/Check", addr);
+        let resp = CLIENT
+            .post(&url)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(encode_request(&self.service))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(Error::probe(format!("unexpected status {}", resp.status())));
+        }
+        // failed calls come with no message and grpc-status in the headers,
+        //  the trailers of a successful one are of no interest
+        if let Some(code) = resp.headers().get("grpc-status") {
+            if code != "0" {
+                let message = resp
+                    .headers()
+                    .get("grpc-message")
+                    .and_then(|m| m.to_str().ok())
+                    .unwrap_or_default();
+                return Err(Error::probe(format!("grpc status {:?}: {}", code, message)));
+            }
+        }
+        let status = decode_response(&resp.bytes().await?)?;
+        if status != SERVING {
+            return Err(Error::probe(format!("not serving, status {}", status)));
+        }
+        Ok(())
+    }
+}
+
+// a port named grpc or grpc-*, or with appProtocol grpc
+pub(crate) fn is_grpc_port(name: Option<&str>, app_protocol: Option<&str>) -> bool {
+    let name = name.unwrap_or_default();
+    name == "grpc" || name.starts_with("grpc-") || app_protocol == Some("grpc")
+}
+
+// HealthCheckRequest { string service = 1; } in a gRPC frame
+fn encode_request(service: &str) -> Vec<u8> {
+    let mut msg = Vec::new();
+    if !service.is_empty() {
+        msg.push(0x0a);
+        encode_varint(service.len() as u64, &mut msg);
+        msg.extend_from_slice(service.as_bytes());
+    }
+    // not compressed, followed by the length of the message
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    frame.extend(msg);
+    frame
+}
+
+// status of HealthCheckResponse { ServingStatus status = 1; } in a gRPC frame
+fn decode_response(frame: &[u8]) -> Result<u64> {
+    let invalid = || Error::probe("invalid health check response");
+    if frame.len() < 5 || frame[0] != 0 {
+        return Err(invalid());
+    }
+    let len = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+    let mut msg = frame.get(5..5 + len).ok_or_else(invalid)?;
+
+    // unset is UNKNOWN
+    let mut status = 0;
+    while !msg.is_empty() {
+        let key = decode_varint(&mut msg).ok_or_else(invalid)?;
+        match key & 0x07 {
+            0 => {
+                let value = decode_varint(&mut msg).ok_or_else(invalid)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            2 => {
+                let len = decode_varint(&mut msg).ok_or_else(invalid)? as usize;
+                msg = msg.get(len..).ok_or_else(invalid)?;
+            }
+            1 => msg = msg.get(8..).ok_or_else(invalid)?,
+            5 => msg = msg.get(4..).ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(status)
+}
+
+fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (i, b) in buf.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec() {
+        assert_eq!(encode_request(""), vec![0, 0, 0, 0, 0]);
+        assert_eq!(
+            encode_request("api"),
+            vec![0, 0, 0, 0, 5, 0x0a, 3, b'a', b'p', b'i']
+        );
+
+        assert_eq!(decode_response(&[0, 0, 0, 0, 2, 0x08, 1]).unwrap(), SERVING);
+        assert_eq!(decode_response(&[0, 0, 0, 0, 2, 0x08, 2]).unwrap(), 2);
+        assert_eq!(decode_response(&[0, 0, 0, 0, 0]).unwrap(), 0);
+        // an unknown field before the status
+        assert_eq!(
+            decode_response(&[0, 0, 0, 0, 5, 0x12, 1, b'x', 0x08, 1]).unwrap(),
+            SERVING
+        );
+        assert!(decode_response(&[0, 0, 0, 0, 2, 0x08]).is_err());
+        assert!(decode_response(&[]).is_err());
+
+        let mut buf = Vec::new();
+        encode_varint(300, &mut buf);
+        assert_eq!(decode_varint(&mut buf.as_slice()), Some(300));
+    }
+
+    #[test]
+    fn grpc_port() {
+        assert!(is_grpc_port(Some("grpc"), None));
+        assert!(is_grpc_port(Some("grpc-api"), None));
+        assert!(is_grpc_port(Some("api"), Some("grpc")));
+        assert!(!is_grpc_port(Some("http"), None));
+        assert!(!is_grpc_port(None, None));
+    }
+}
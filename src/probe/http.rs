@@ -0,0 +1,209 @@
+use crate::error::{Error, Result};
+use regex::Regex;
+use reqwest::{header, redirect, Method};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use super::BodyMatch;
+
+const DEFAULT_STATUS: (u16, u16) = (200, 399);
+// clients kept for verifying the host of endpoints, more than that and
+//  they're dropped to be built again for the endpoints still around
+const MAX_HOST_CLIENTS: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct HttpProbe {
+    pub method: Method,
+    pub path: String,
+    // sent as the Host header, and checked against the certificate with TLS
+    pub host: Option<String>,
+    // inclusive range of status codes taken as healthy
+    pub status: (u16, u16),
+    pub body: Option<BodyMatch>,
+    pub tls: bool,
+    // don't verify the certificate
+    pub insecure: bool,
+    client: reqwest::Client,
+    // by endpoint, each resolving host to it
+    host_clients: Arc<Mutex<HashMap<SocketAddr, reqwest::Client>>>,
+}
+
+impl HttpProbe {
+    // build from the key=value options of a probe spec
+    pub fn new(tls: bool, opts: &[(&str, Option<&str>)]) -> Result<Self> {
+        let mut method = Method::GET;
+        let mut path = "/".to_owned();
+        let mut host = None;
+        let mut status = DEFAULT_STATUS;
+        let mut body = None;
+        let mut insecure = false;
+        for (k, v) in opts {
+            match (*k, *v) {
+                ("method", Some(v)) => {
+                    method = Method::from_bytes(v.to_uppercase().as_bytes())
+                        .map_err(|_| Error::config(format!("invalid http method {}", v)))?;
+                }
+                ("path", Some(v)) => path = format!("/{}", v.trim_start_matches('/')),
+                ("host", Some(v)) => host = Some(v.to_owned()),
+                ("status", Some(v)) => status = parse_status(v)?,
+                ("body", Some(v)) => body = Some(BodyMatch::Contains(v.to_owned())),
+                ("regex", Some(v)) => {
+                    let re = Regex::new(v)
+                        .map_err(|e| Error::config(format!("invalid regex {}: {}", v, e)))?;
+                    body = Some(BodyMatch::Regex(re));
+                }
+                ("insecure", None) => insecure = true,
+                ("insecure", Some(v)) => {
+                    insecure = v
+                        .parse()
+                        .map_err(|_| Error::config(format!("invalid insecure {}", v)))?;
+                }
+                (k, _) => return Err(Error::config(format!("invalid http probe option {}", k))),
+            }
+        }
+
+        Ok(Self {
+            method,
+            path,
+            host,
+            status,
+            body,
+            tls,
+            insecure,
+            client: builder(insecure).build()?,
+            host_clients: Default::default(),
+        })
+    }
+
+    // client connecting to addr for the host
+    fn host_client(&self, host: &str, addr: SocketAddr) -> Result<reqwest::Client> {
+        let mut clients = self.host_clients.lock().unwrap();
+        if let Some(client) = clients.get(&addr) {
+            return Ok(client.clone());
+        }
+        if clients.len() >= MAX_HOST_CLIENTS {
+            clients.clear();
+        }
+        let client = builder(false).resolve(host, addr).build()?;
+        clients.insert(addr, client.clone());
+        Ok(client)
+    }
+
+    pub async fn check(&self, addr: SocketAddr) -> Result<()> {
+        let resp = match (&self.host, self.tls && !self.insecure) {
+            // The certificate is for the host rather than the ip of the
+            //  endpoint, so connect to the endpoint by the host name for the
+            //  name to be verified. It takes a client of its own as the
+            //  resolution is per client.
+            (Some(host), true) => {
+                let client = self.host_client(host, addr)?;
+                let url = format!("https://{}:{}{}", host, addr.port(), self.path);
+                client.request(self.method.clone(), &url).send().await?
+            }
+            (host, _) => {
+                let scheme = if self.tls { "https" } else { "http" };
+                let url = format!("{}://{}{}", scheme, addr, self.path);
+                let mut req = self.client.request(self.method.clone(), &url);
+                if let Some(host) = host {
+                    req = req.header(header::HOST, host);
+                }
+                req.send().await?
+            }
+        };
+
+        let status = resp.status().as_u16();
+        if status < self.status.0 || status > self.status.1 {
+            return Err(Error::probe(format!("unexpected status {}", status)));
+        }
+        if let Some(body) = &self.body {
+            if !body.is_match(&resp.text().await?) {
+                return Err(Error::probe("body doesn't match"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn builder(insecure: bool) -> reqwest::ClientBuilder {
+    // a redirect is a response of the endpoint like any other, follow it and
+    //  we may end up probing something else
+    reqwest::Client::builder()
+        .use_rustls_tls()
+        .redirect(redirect::Policy::none())
+        .danger_accept_invalid_certs(insecure)
+}
+
+// 200 or 200-399
+fn parse_status(s: &str) -> Result<(u16, u16)> {
+    let invalid = || Error::config(format!("invalid status {}", s));
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (min, max),
+        None => (s, s),
+    };
+    let min = min.parse().map_err(|_| invalid())?;
+    let max = max.parse().map_err(|_| invalid())?;
+    if min > max {
+        return Err(invalid());
+    }
+    Ok((min, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // serve one response on a random port
+    async fn serve(resp: &'static str) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(resp.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(parse_status("200").unwrap(), (200, 200));
+        assert_eq!(parse_status("200-299").unwrap(), (200, 299));
+        assert!(parse_status("299-200").is_err());
+        assert!(parse_status("2xx").is_err());
+    }
+
+    #[tokio::test]
+    async fn check() {
+        const OK: &str =
+            "HTTP/1.1 200 OK\r\ncontent-length: 9\r\nconnection: close\r\n\r\nstatus=up";
+        const DOWN: &str =
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+        let probe = HttpProbe::new(false, &[("path", Some("/healthz"))]).unwrap();
+        probe.check(serve(OK).await).await.unwrap();
+        probe.check(serve(DOWN).await).await.unwrap_err();
+
+        let probe = HttpProbe::new(false, &[("status", Some("500-599"))]).unwrap();
+        probe.check(serve(DOWN).await).await.unwrap();
+
+        let probe = HttpProbe::new(false, &[("body", Some("up"))]).unwrap();
+        probe.check(serve(OK).await).await.unwrap();
+        let probe = HttpProbe::new(false, &[("regex", Some("^status=(ok|healthy)$"))]).unwrap();
+        probe.check(serve(OK).await).await.unwrap_err();
+    }
+
+    #[test]
+    fn host_client() {
+        let probe = HttpProbe::new(true, &[("host", Some("api.example.com"))]).unwrap();
+        let addr = "127.0.0.1:443".parse().unwrap();
+        probe.host_client("api.example.com", addr).unwrap();
+        probe.host_client("api.example.com", addr).unwrap();
+        assert_eq!(probe.host_clients.lock().unwrap().len(), 1);
+        let other = "127.0.0.2:443".parse().unwrap();
+        probe.clone().host_client("api.example.com", other).unwrap();
+        assert_eq!(probe.host_clients.lock().unwrap().len(), 2);
+    }
+}
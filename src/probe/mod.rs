@@ -0,0 +1,940 @@
+use log::{debug, error};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::sync::{oneshot, watch, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+use crate::config::{Config, ServiceConfig};
+use crate::error::{Error, Result};
+use crate::kube::{Endpoint, EndpointStatus, Override, ProbeResult, Protocol, Service, Services};
+use crate::metrics::{self, Operation};
+
+mod grpc;
+mod http;
+mod schedule;
+mod udp;
+
+pub(crate) use schedule::Schedule;
+
+// what a response has to have for the endpoint to be healthy
+#[derive(Debug, Clone)]
+pub(crate) enum BodyMatch {
+    Contains(String),
+    Regex(regex::Regex),
+}
+
+impl BodyMatch {
+    fn is_match(&self, body: &str) -> bool {
+        match self {
+            BodyMatch::Contains(s) => body.contains(s.as_str()),
+            BodyMatch::Regex(re) => re.is_match(body),
+        }
+    }
+}
+
+// how to tell whether an endpoint is healthy
+#[derive(Debug, Clone)]
+pub(crate) enum Probe {
+    // it accepts connections
+    Tcp,
+    Http(http::HttpProbe),
+    Grpc(grpc::GrpcProbe),
+    Udp(udp::UdpProbe),
+    Dns(udp::DnsProbe),
+}
+
+impl Probe {
+    // protocol of the endpoints this probe works for
+    fn protocol(&self) -> Protocol {
+        match self {
+            Probe::Tcp | Probe::Http(_) | Probe::Grpc(_) => Protocol::TCP,
+            Probe::Udp(_) | Probe::Dns(_) => Protocol::UDP,
+        }
+    }
+
+    // deadline is when the probe times out, which those waiting for nothing
+    //  to come back wait until
+    pub async fn check(&self, addr: SocketAddr, deadline: time::Instant) -> Result<()> {
+        match self {
+            Probe::Tcp => {
+                tokio::net::TcpStream::connect(addr).await?;
+                Ok(())
+            }
+            Probe::Http(probe) => probe.check(addr).await,
+            Probe::Grpc(probe) => probe.check(addr).await,
+            Probe::Udp(probe) => probe.check(addr, deadline).await,
+            Probe::Dns(probe) => probe.check(addr).await,
+        }
+    }
+}
+
+// TYPE[,KEY[=VALUE]...]
+impl FromStr for Probe {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut fields = s.split(',');
+        let type_ = fields.next().unwrap_or_default();
+        let opts: Vec<(&str, Option<&str>)> = fields
+            .map(|field| match field.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (field, None),
+            })
+            .collect();
+        match type_ {
+            "tcp" if opts.is_empty() => Ok(Probe::Tcp),
+            "tcp" => Err(Error::config("tcp probe takes no option")),
+            "http" => Ok(Probe::Http(http::HttpProbe::new(false, &opts)?)),
+            "https" => Ok(Probe::Http(http::HttpProbe::new(true, &opts)?)),
+            "grpc" => Ok(Probe::Grpc(grpc::GrpcProbe::new(&opts)?)),
+            "udp" => Ok(Probe::Udp(udp::UdpProbe::new(&opts)?)),
+            "dns" => Ok(Probe::Dns(udp::DnsProbe::new(&opts)?)),
+            _ => Err(Error::config(format!("unknown probe type {}", type_))),
+        }
+    }
+}
+
+// probe for endpoints of the matching service and port
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeRule {
+    // NAME, NAMESPACE/NAME or * for every service
+    pub service: String,
+    // name or number, None for every port
+    pub port: Option<String>,
+    pub probe: Probe,
+}
+
+impl ProbeRule {
+    fn matches(&self, svc: &Service, ep: &Endpoint) -> bool {
+        if self.probe.protocol() != ep.protocol {
+            return false;
+        }
+        let svc_matches = match self.service.split_once('/') {
+            _ if self.service == "*" => true,
+            Some((ns, name)) => ns == svc.namespace && name == svc.name,
+            None => self.service == svc.name,
+        };
+        let port_matches = match &self.port {
+            None => true,
+            Some(port) => {
+                ep.port_name.as_ref() == Some(port) || *port == ep.addr.port().to_string()
+            }
+        };
+        svc_matches && port_matches
+    }
+}
+
+// SERVICE[:PORT]=PROBE
+impl FromStr for ProbeRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (target, probe) = s
+            .split_once('=')
+            .ok_or_else(|| Error::config(format!("invalid probe {}, no =", s)))?;
+        let (service, port) = match target.split_once(':') {
+            Some((service, port)) => (service, Some(port.to_owned())),
+            None => (target, None),
+        };
+        if service.is_empty() {
+            return Err(Error::config(format!("invalid probe {}, no service", s)));
+        }
+        Ok(Self {
+            service: service.to_owned(),
+            port,
+            probe: Probe::from_str(probe)?,
+        })
+    }
+}
+
+// Probes of all services. Rules only match endpoints of the protocol their
+//  probe works for, and endpoints matching no rule are probed by what their
+//  port looks like: gRPC health checking or a TCP connect for TCP, a DNS
+//  query or an empty datagram for UDP.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Probes {
+    rules: Vec<ProbeRule>,
+}
+
+impl Probes {
+    pub fn new(rules: Vec<ProbeRule>) -> Self {
+        Self { rules }
+    }
+
+    // Rules for a port are preferred over rules for a whole service, and
+    //  among the same kind the last one given wins. With no rule matching,
+    //  fallback is used if it works for the protocol of ep.
+    pub fn get(&self, svc: &Service, ep: &Endpoint, fallback: Option<&Probe>) -> Probe {
+        let mut matching = self.rules.iter().rev().filter(|rule| rule.matches(svc, ep));
+        match matching.clone().find(|rule| rule.port.is_some()) {
+            Some(rule) => rule.probe.clone(),
+            None => matching
+                .next()
+                .map(|rule| &rule.probe)
+                .or_else(|| fallback.filter(|probe| probe.protocol() == ep.protocol))
+                .cloned()
+                .unwrap_or_else(|| default_probe(ep)),
+        }
+    }
+}
+
+fn default_probe(ep: &Endpoint) -> Probe {
+    let name = ep.port_name.as_deref();
+    match ep.protocol {
+        Protocol::TCP if grpc::is_grpc_port(name, ep.app_protocol.as_deref()) => {
+            Probe::Grpc(grpc::GrpcProbe::default())
+        }
+        Protocol::TCP => Probe::Tcp,
+        Protocol::UDP if udp::is_dns_port(name, ep.addr.port()) => {
+            Probe::Dns(udp::DnsProbe::default())
+        }
+        Protocol::UDP => Probe::Udp(udp::UdpProbe::default()),
+    }
+}
+
+// how often endpoints are checked for being due to be probed
+const TICK: Duration = Duration::from_millis(50);
+
+// Probes every endpoint on its own schedule. Services are probed
+//  concurrently, and a probe is never waited for by anything but applying
+//  its own result.
+pub(crate) struct Prober {
+    probes: Arc<Probes>,
+    config: watch::Receiver<Arc<Config>>,
+    // in-flight probes of all services
+    global: Arc<Semaphore>,
+    // held for reading by a probe until its result is applied, false once
+    //  stopped
+    applying: Arc<RwLock<bool>>,
+    // in-flight probes of one service
+    per_svc: usize,
+    // of every service by key, so that globs aren't matched every tick
+    svc_states: HashMap<String, SvcState>,
+}
+
+struct SvcState {
+    limit: Arc<Semaphore>,
+    config: ServiceConfig,
+}
+
+impl Prober {
+    pub fn new(
+        probes: Arc<Probes>,
+        config: watch::Receiver<Arc<Config>>,
+        global: usize,
+        per_svc: usize,
+    ) -> Self {
+        Self {
+            probes,
+            config,
+            global: Arc::new(Semaphore::new(global)),
+            applying: Arc::new(RwLock::new(true)),
+            per_svc,
+            svc_states: HashMap::new(),
+        }
+    }
+
+    // probe until stop, then wait for the probes in flight to be applied
+    pub async fn run(mut self, svcs: Services, mut stop: oneshot::Receiver<()>) {
+        let mut interval = time::interval(TICK);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.probe(&svcs).await,
+                _ = &mut stop => break,
+            }
+        }
+        // Closing the semaphore keeps probes waiting for a permit from ever
+        //  starting, and the write lock waits for those which got one to be
+        //  applied, so nothing is removed or restored after this returns.
+        self.global.close();
+        *self.applying.write().await = false;
+    }
+
+    // start probing endpoints which are due
+    pub async fn probe(&mut self, svcs: &Services) {
+        let svcs: Vec<(String, Arc<RwLock<Service>>)> = svcs
+            .read()
+            .await
+            .iter()
+            .map(|(key, svc)| (key.clone(), svc.clone()))
+            .collect();
+        self.svc_states
+            .retain(|key, _| svcs.iter().any(|(k, _)| k == key));
+        // reloaded, semaphores are kept not to let more probes in
+        if self.config.has_changed().unwrap_or(false) {
+            let config = self.config.borrow_and_update().clone();
+            for (key, state) in &mut self.svc_states {
+                let (namespace, name) = key.split_once('/').unwrap_or_default();
+                state.config = config.service(namespace, name);
+            }
+        }
+        let config = self.config.borrow().clone();
+        for (key, svc) in svcs {
+            let per_svc = self.per_svc;
+            let state = self.svc_states.entry(key).or_insert_with_key(|key| {
+                let (namespace, name) = key.split_once('/').unwrap_or_default();
+                SvcState {
+                    limit: Arc::new(Semaphore::new(per_svc)),
+                    config: config.service(namespace, name),
+                }
+            });
+            let limits = Limits {
+                global: self.global.clone(),
+                per_svc: state.limit.clone(),
+                applying: self.applying.clone(),
+            };
+            probe_svc(svc, &self.probes, &state.config, limits);
+        }
+    }
+}
+
+// caps on in-flight probes a probe has to get a permit of each, and whether
+//  results may still be applied
+#[derive(Clone)]
+struct Limits {
+    global: Arc<Semaphore>,
+    per_svc: Arc<Semaphore>,
+    applying: Arc<RwLock<bool>>,
+}
+
+// Endpoints are probed in parallel without holding the lock of the service,
+//  so that a slow one doesn't eat into the timeout of the others. The lock is
+//  only taken to pick the endpoints due and to apply the results.
+fn probe_svc(
+    svc: Arc<RwLock<Service>>,
+    probes: &Probes,
+    config: &ServiceConfig,
+    limits: Limits,
+) -> Vec<JoinHandle<()>> {
+    // busy applying results, next tick
+    let mut svc_writer = match svc.try_write() {
+        Ok(svc_writer) => svc_writer,
+        Err(_) => return vec![],
+    };
+    // disabled by its owner, put back what we removed and leave it alone.
+    //  Restoring is tried when the removed endpoints are due, so that a
+    //  failure backs off the way their probes do.
+    if !svc_writer.annotations.enabled {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for ep in &mut svc_writer.endpoints {
+            if ep.status == EndpointStatus::Removed && config.schedule.due(ep, now) {
+                due.push(ep.addr);
+            }
+        }
+        if due.is_empty() {
+            return vec![];
+        }
+        drop(svc_writer);
+        return vec![tokio::spawn(async move {
+            let mut svc = svc.write().await;
+            let res = svc.restore_all().await;
+            if let Err(e) = &res {
+                error!("failed to restore eps of disabled {}: {}", svc.key(), e);
+            }
+            for ep in &mut svc.endpoints {
+                if !due.contains(&ep.addr) {
+                    continue;
+                }
+                ep.probe_state.in_flight = false;
+                if res.is_err() {
+                    ep.probe_state.backoff = ep.probe_state.backoff.saturating_add(1);
+                }
+            }
+        })];
+    }
+    let (namespace, name) = (svc_writer.namespace.clone(), svc_writer.name.clone());
+    let now = Instant::now();
+    // annotations of the service win over the config file
+    let annotations = svc_writer.annotations.clone();
+    let mut targets = Vec::<(SocketAddr, Protocol, Probe)>::new();
+    for i in 0..svc_writer.endpoints.len() {
+        if !config.selects(&svc_writer.endpoints[i]) {
+            continue;
+        }
+        if config.schedule.due(&mut svc_writer.endpoints[i], now) {
+            let ep = &svc_writer.endpoints[i];
+            let probe = match &annotations.probe {
+                Some(probe) if probe.protocol() == ep.protocol => probe.clone(),
+                _ => probes.get(&svc_writer, ep, config.probe.as_ref()),
+            };
+            targets.push((ep.addr, ep.protocol.clone(), probe));
+        }
+    }
+    let timeout = Duration::from_millis(annotations.timeout.unwrap_or(config.timeout));
+    drop(svc_writer);
+
+    let mut jhs = Vec::<JoinHandle<_>>::new();
+    for (addr, protocol, probe) in targets {
+        let svc = svc.clone();
+        let limits = limits.clone();
+        let (namespace, name) = (namespace.clone(), name.clone());
+        jhs.push(tokio::spawn(async move {
+            // The one of the service first, not to hold a global permit
+            //  while waiting for the others of the service.
+            // Only the global one is closed, once stopped.
+            let per_svc = limits.per_svc.acquire().await.unwrap();
+            let global = match limits.global.acquire().await {
+                Ok(global) => global,
+                Err(_) => return,
+            };
+            // held until applied for Prober::run to know when it's done
+            let applying = limits.applying.read().await;
+            if !*applying {
+                return;
+            }
+            // the timeout starts once it's got the permits
+            let start = Instant::now();
+            let deadline = time::Instant::now() + timeout;
+            let res = match time::timeout_at(deadline, probe.check(addr, deadline)).await {
+                Ok(res) => res,
+                Err(_) => Err(Error::timeout("timed out")),
+            };
+            // Applying may write to k8s and alert, which is no reason to hold
+            //  up probes of other services
+            drop(global);
+            drop(per_svc);
+            metrics::probe_done(&namespace, &name, &addr.to_string(), start.elapsed());
+            metrics::probe_result(&namespace, &name, &res);
+            apply(svc, addr, protocol, res).await;
+            drop(applying);
+        }));
+    }
+    jhs
+}
+
+// Count the result of probing the endpoint of addr, and remove or restore it
+//  once it crosses the threshold. It's looked up by address, as endpoints
+//  come and go while it's probed, and is gone if its pod is.
+async fn apply(svc: Arc<RwLock<Service>>, addr: SocketAddr, protocol: Protocol, res: Result<()>) {
+    let mut svc = svc.write().await;
+    let i = match svc
+        .endpoints
+        .iter()
+        .position(|ep| ep.addr == addr && ep.protocol == protocol)
+    {
+        Some(i) => i,
+        None => return,
+    };
+    let ep = &mut svc.endpoints[i];
+    ep.probe_state.in_flight = false;
+    ep.probe_state.last = Some(ProbeResult {
+        at: SystemTime::now(),
+        error: res.as_ref().err().map(|e| e.to_string()),
+    });
+    match (ep.admin_override(Instant::now()), &res) {
+        (Some(Override::Drained), _) => {
+            debug!("{:?} drained, ignoring probe", addr);
+            return;
+        }
+        (Some(Override::Restored), Err(e)) => {
+            debug!("{:?} force restored, ignoring failed probe: {}", addr, e);
+            return;
+        }
+        _ => {}
+    }
+    match res {
+        Ok(_) => {
+            debug!("{:?} healthy", addr);
+            ep.probe_state.backoff = 0;
+            if !ep.up() {
+                return;
+            }
+            match svc.restore_ep(i).await {
+                // rather forgotten if its pod is gone
+                Ok(_) if svc.endpoints.iter().any(|ep| ep.addr == addr) => {
+                    metrics::operation(&svc.namespace, &svc.name, Operation::Restore)
+                }
+                Ok(_) => {}
+                Err(e) => error!("failed to restore ep: {:?}: {}", addr, e),
+            }
+        }
+        Err(e) => {
+            error!("failed to probe {:?}, {}", addr, e);
+            if ep.status == EndpointStatus::Removed {
+                ep.probe_state.backoff = ep.probe_state.backoff.saturating_add(1);
+            }
+            if !ep.down() {
+                return;
+            }
+            match svc.remove_ep(i).await {
+                Ok(_) => metrics::operation(&svc.namespace, &svc.name, Operation::Remove),
+                Err(e) => error!("failed to remove ep: {:?}: {}", addr, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kube;
+    use kube::Threshold;
+    use std::time::{Duration, Instant};
+    use std::{net::SocketAddr, str::FromStr, sync::Arc};
+    use tokio::sync::RwLock;
+
+    fn limits() -> super::Limits {
+        super::Limits {
+            global: Arc::new(tokio::sync::Semaphore::new(8)),
+            per_svc: Arc::new(tokio::sync::Semaphore::new(2)),
+            applying: Arc::new(RwLock::new(true)),
+        }
+    }
+
+    fn config() -> crate::config::ServiceConfig {
+        crate::config::ServiceConfig {
+            schedule: super::Schedule {
+                interval: Duration::from_millis(1000),
+                removed_interval: Duration::from_millis(1000),
+                max_removed_interval: Duration::from_millis(1000),
+                jitter: 0,
+            },
+            timeout: 100,
+            ..Default::default()
+        }
+    }
+
+    // due to be probed right away
+    fn due() -> kube::ProbeState {
+        kube::ProbeState {
+            next: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    // a healthy endpoint due to be probed right away
+    fn endpoint(addr: SocketAddr, protocol: kube::Protocol) -> kube::Endpoint {
+        kube::Endpoint {
+            addr,
+            protocol,
+            port_name: None,
+            app_protocol: None,
+            subset: 0,
+            status: kube::EndpointStatus::Healthy,
+            counter: kube::Counter { up: 0, down: 0 },
+            threshold: Threshold {
+                restore: 3,
+                remove: 3,
+            },
+            probe_state: due(),
+            admin: None,
+        }
+    }
+
+    // default/test of endpoints, with nothing in k8s
+    fn service(endpoints: Vec<kube::Endpoint>) -> kube::Service {
+        kube::Service {
+            name: "test".to_owned(),
+            namespace: "default".to_owned(),
+            endpoints,
+            our_version: "0".to_owned(),
+            repr: Default::default(),
+            slices: None,
+            annotations: Default::default(),
+            alerter: Arc::new(crate::alert::Alert::default()),
+            client: kube::tests::client(),
+        }
+    }
+
+    // probe svc once and wait for the results to be applied
+    async fn probe_once(svc: &Arc<RwLock<kube::Service>>, probes: super::Probes) {
+        let jhs = super::probe_svc(svc.clone(), &probes, &config(), limits());
+        for jh in jhs {
+            jh.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn do_probe() {
+        let eps = vec![
+            endpoint(
+                SocketAddr::from_str("127.0.0.1:44307").unwrap(),
+                kube::Protocol::TCP,
+            ),
+            endpoint(
+                SocketAddr::from_str("127.0.0.1:80").unwrap(),
+                kube::Protocol::TCP,
+            ),
+        ];
+
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          creationTimestamp: 2019-03-20T07:23:28Z
+          name: ephc-test
+          namespace: default
+          resourceVersion: \"82479279\"
+          selfLink: /api/v1/namespaces/default/endpoints/ephc-test
+          uid: 0ec10531-4ae1-11e9-9c9c-f86eee307061
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          - ip: 172.0.1.5
+          - ip: 172.0.1.6
+          ports:
+          - name: port80
+            port: 31000
+            protocol: TCP
+          - name: port82
+            port: 31002
+            protocol: TCP
+          - name: port81
+            port: 31001
+            protocol: TCP";
+
+        let svc = Arc::new(RwLock::new(kube::Service {
+            repr: kube::yaml::ServiceRepr::from_str(yml_str).unwrap(),
+            ..service(eps)
+        }));
+
+        probe_once(&svc, super::Probes::default()).await;
+
+        let svc_clone = svc.read().await;
+        println!("eps after edit: {:?}", svc_clone.endpoints);
+    }
+
+    #[tokio::test]
+    async fn slow_endpoint() {
+        // accepts connections
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // never responds
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let svc = Arc::new(RwLock::new(service(vec![
+            endpoint(silent.local_addr().unwrap(), kube::Protocol::UDP),
+            endpoint(listener.local_addr().unwrap(), kube::Protocol::TCP),
+        ])));
+        let probes = super::Probes::new(vec![super::ProbeRule::from_str(
+            "test=udp,payload=ping,expect=pong",
+        )
+        .unwrap()]);
+
+        probe_once(&svc, probes).await;
+
+        // the slow one timed out without taking the other one with it
+        let svc = svc.read().await;
+        assert_eq!(svc.endpoints[0].counter.down, 1);
+        assert_eq!(svc.endpoints[1].counter.down, 0);
+    }
+
+    #[tokio::test]
+    async fn prober_skips_in_flight() {
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let svc = Arc::new(RwLock::new(service(vec![endpoint(
+            silent.local_addr().unwrap(),
+            kube::Protocol::UDP,
+        )])));
+        let svcs: kube::Services = Default::default();
+        svcs.write()
+            .await
+            .insert("default/test".to_owned(), svc.clone());
+        let probes = super::Probes::new(vec![super::ProbeRule::from_str(
+            "test=udp,payload=ping,expect=pong",
+        )
+        .unwrap()]);
+        let mut prober = super::Prober::new(
+            Arc::new(probes),
+            tokio::sync::watch::channel(Arc::new(crate::config::Config::new(config()))).1,
+            8,
+            2,
+        );
+
+        prober.probe(&svcs).await;
+        // still being probed
+        prober.probe(&svcs).await;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(svc.read().await.endpoints[0].counter.down, 1);
+
+        // not due yet
+        prober.probe(&svcs).await;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(svc.read().await.endpoints[0].counter.down, 1);
+
+        svc.write().await.endpoints[0].probe_state.next = Some(Instant::now());
+        prober.probe(&svcs).await;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(svc.read().await.endpoints[0].counter.down, 2);
+
+        // stopping waits for the probe in flight to be applied
+        svc.write().await.endpoints[0].probe_state.next = Some(Instant::now());
+        let (stop_probe, stop) = tokio::sync::oneshot::channel();
+        let jh = tokio::spawn(prober.run(svcs.clone(), stop));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        stop_probe.send(()).unwrap();
+        jh.await.unwrap();
+        assert_eq!(svc.read().await.endpoints[0].counter.down, 3);
+    }
+
+    #[tokio::test]
+    async fn permit_released_before_apply() {
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut svc = kube::Service::new(
+            kube::tests::YML_STR.to_owned(),
+            Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.endpoints.truncate(1);
+        svc.endpoints[0].addr = silent.local_addr().unwrap();
+        svc.endpoints[0].protocol = kube::Protocol::UDP;
+        svc.endpoints[0].probe_state = due();
+        let svc = Arc::new(RwLock::new(svc));
+        let limits = super::Limits {
+            global: Arc::new(tokio::sync::Semaphore::new(1)),
+            ..limits()
+        };
+
+        let jhs = super::probe_svc(svc.clone(), &Default::default(), &config(), limits.clone());
+        // applying waits on the lock of the service, other services go on
+        let svc_writer = svc.write().await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(limits.global.available_permits(), 1);
+        drop(svc_writer);
+        for jh in jhs {
+            jh.await.unwrap();
+        }
+        assert!(!svc.read().await.endpoints[0].probe_state.in_flight);
+    }
+
+    #[tokio::test]
+    async fn forgotten_while_probing() {
+        // the address of a pod since gone answers, the other one never does
+        let answering = tokio::net::TcpListener::bind("127.0.0.4:0").await.unwrap();
+        let port = answering.local_addr().unwrap().port();
+        let silent = tokio::net::TcpListener::bind(("127.0.0.5", port))
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            loop {
+                let (mut stream, _) = answering.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            loop {
+                conns.push(silent.accept().await.unwrap());
+            }
+        });
+        let yml_str = format!(
+            "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: test
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 127.0.0.4
+            targetRef: {{kind: Pod, namespace: default, name: test-0, uid: \"0\"}}
+          - ip: 127.0.0.5
+          ports:
+          - name: http
+            port: {}
+            protocol: TCP",
+            port
+        );
+        // knows of no pod
+        let mut client = kube::tests::api_server(|_, _| ("404 Not Found", "{}".to_owned())).await;
+        client.dry_run = true;
+        let client = Arc::new(client);
+        let new = |yml_str: String| {
+            kube::Service::new(
+                yml_str,
+                Threshold {
+                    restore: 1,
+                    remove: 3,
+                },
+                Arc::new(crate::alert::Alert::default()),
+                client.clone(),
+            )
+            .unwrap()
+            .unwrap()
+        };
+        let mut svc = new(yml_str);
+        svc.remove_ep(0).await.unwrap();
+        let mut svc = new(svc.repr.to_yaml().unwrap());
+        for ep in &mut svc.endpoints {
+            ep.probe_state = due();
+        }
+        let svc = Arc::new(RwLock::new(svc));
+        let probes = super::Probes::new(vec![super::ProbeRule::from_str("test=http").unwrap()]);
+
+        // the first is forgotten while the second is still being probed
+        probe_once(&svc, probes).await;
+        let svc = svc.read().await;
+        assert_eq!(svc.endpoints.len(), 1);
+        let ep = &svc.endpoints[0];
+        assert_eq!(ep.addr.ip().to_string(), "127.0.0.5");
+        assert_eq!(ep.counter.down, 1);
+        assert!(!ep.probe_state.in_flight);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let mut svc = kube::Service::new(
+            kube::tests::YML_STR.to_owned(),
+            Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.remove_ep(0).await.unwrap();
+        svc.annotations.enabled = false;
+        for ep in &mut svc.endpoints {
+            ep.probe_state = due();
+        }
+        let svc = Arc::new(RwLock::new(svc));
+
+        // what was removed is put back, nothing is probed
+        for jh in super::probe_svc(svc.clone(), &Default::default(), &config(), limits()) {
+            jh.await.unwrap();
+        }
+        let svc = svc.read().await;
+        assert!(svc
+            .endpoints
+            .iter()
+            .all(|ep| ep.status == kube::EndpointStatus::Healthy && ep.probe_state.last.is_none()));
+    }
+
+    #[tokio::test]
+    async fn disabled_backoff() {
+        // k8s refuses every write
+        let client =
+            kube::tests::api_server(|_, _| ("500 Internal Server Error", "{}".to_owned())).await;
+        let mut svc = kube::Service::new(
+            kube::tests::YML_STR.to_owned(),
+            Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::dry_run_client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.remove_ep(0).await.unwrap();
+        svc.client = Arc::new(client);
+        svc.endpoints[0].probe_state = due();
+        svc.annotations.enabled = false;
+        let svc = Arc::new(RwLock::new(svc));
+
+        // a failed restore isn't retried until the removed endpoint is due
+        let jhs = super::probe_svc(svc.clone(), &Default::default(), &config(), limits());
+        assert_eq!(jhs.len(), 1);
+        for jh in jhs {
+            jh.await.unwrap();
+        }
+        {
+            let svc = svc.read().await;
+            let ep = &svc.endpoints[0];
+            assert_eq!(ep.status, kube::EndpointStatus::Removed);
+            assert_eq!(ep.probe_state.backoff, 1);
+            assert!(!ep.probe_state.in_flight);
+        }
+        assert!(super::probe_svc(svc, &Default::default(), &config(), limits()).is_empty());
+    }
+
+    #[test]
+    fn probes_get() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: prod
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          ports:
+          - name: http
+            port: 8080
+            protocol: TCP
+          - name: metrics
+            port: 9090
+            protocol: TCP
+          - port: 6379
+            protocol: TCP
+          - name: grpc
+            port: 9000
+            protocol: TCP
+          - name: dns
+            port: 53
+            protocol: UDP";
+        let svc = kube::Service::new(
+            yml_str.to_owned(),
+            Threshold::default(),
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        let rules = [
+            "*=http,path=/healthz",
+            "other=tcp",
+            "prod/api:metrics=http,path=/metrics,status=200",
+            "api:6379=tcp",
+        ];
+        let probes = super::Probes::new(
+            rules
+                .iter()
+                .map(|rule| super::ProbeRule::from_str(rule).unwrap())
+                .collect(),
+        );
+        let paths: Vec<String> = svc
+            .endpoints
+            .iter()
+            .map(|ep| match probes.get(&svc, ep, None) {
+                super::Probe::Http(probe) => probe.path,
+                super::Probe::Grpc(_) => "grpc".to_owned(),
+                super::Probe::Dns(_) => "dns".to_owned(),
+                _ => "tcp".to_owned(),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["/healthz", "/metrics", "tcp", "/healthz", "dns"]
+        );
+
+        let probes = super::Probes::default();
+        let types: Vec<&str> = svc
+            .endpoints
+            .iter()
+            .map(|ep| match probes.get(&svc, ep, None) {
+                super::Probe::Grpc(_) => "grpc",
+                super::Probe::Dns(_) => "dns",
+                _ => "tcp",
+            })
+            .collect();
+        assert_eq!(types, vec!["tcp", "tcp", "tcp", "grpc", "dns"]);
+
+        // one from the config file is only used if its protocol fits
+        let fallback = super::Probe::from_str("http,path=/ready").unwrap();
+        let types: Vec<&str> = svc
+            .endpoints
+            .iter()
+            .map(|ep| match probes.get(&svc, ep, Some(&fallback)) {
+                super::Probe::Http(_) => "http",
+                super::Probe::Dns(_) => "dns",
+                _ => "tcp",
+            })
+            .collect();
+        assert_eq!(types, vec!["http", "http", "http", "http", "dns"]);
+
+        assert!(super::ProbeRule::from_str("api").is_err());
+        assert!(super::ProbeRule::from_str("api=ftp").is_err());
+        assert!(super::ProbeRule::from_str("api:grpc=grpc,service=api").is_ok());
+        assert!(super::ProbeRule::from_str("api=http,status=abc").is_err());
+    }
+}
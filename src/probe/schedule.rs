@@ -0,0 +1,115 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+use crate::kube::{Endpoint, EndpointStatus};
+
+// when each endpoint is probed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Schedule {
+    // between probes of a healthy endpoint
+    pub interval: Duration,
+    // between probes of a removed endpoint, doubled on every failed probe up
+    //  to max_removed_interval
+    pub removed_interval: Duration,
+    pub max_removed_interval: Duration,
+    // percent of the interval the next probe is randomly moved by either way,
+    //  so that probes don't all go out at the same instant
+    pub jitter: u32,
+}
+
+impl Schedule {
+    // whether ep is due to be probed at now, if so the next probe is scheduled
+    //  and ep is in flight until the result is applied
+    pub fn due(&self, ep: &mut Endpoint, now: Instant) -> bool {
+        let interval = self.interval_of(ep);
+        let state = &mut ep.probe_state;
+        if state.in_flight {
+            return false;
+        }
+        match state.next {
+            // spread the first probes of new endpoints over an interval
+            None => {
+                state.next = Some(now + interval.mul_f64(rand::thread_rng().gen()));
+                false
+            }
+            Some(next) if next > now => false,
+            Some(_) => {
+                state.next = Some(now + self.jittered(interval));
+                state.in_flight = true;
+                true
+            }
+        }
+    }
+
+    fn interval_of(&self, ep: &Endpoint) -> Duration {
+        match ep.status {
+            EndpointStatus::Healthy => self.interval,
+            EndpointStatus::Removed => {
+                let factor = 2u32.saturating_pow(ep.probe_state.backoff);
+                let max = self.max_removed_interval.max(self.removed_interval);
+                self.removed_interval.saturating_mul(factor).min(max)
+            }
+        }
+    }
+
+    fn jittered(&self, interval: Duration) -> Duration {
+        let jitter = self.jitter.min(100) as f64 / 100.0;
+        if jitter == 0.0 {
+            return interval;
+        }
+        interval.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kube;
+
+    #[test]
+    fn due() {
+        let schedule = Schedule {
+            interval: Duration::from_secs(1),
+            removed_interval: Duration::from_secs(2),
+            max_removed_interval: Duration::from_secs(5),
+            jitter: 10,
+        };
+        let mut ep = kube::Endpoint {
+            addr: "127.0.0.1:80".parse().unwrap(),
+            protocol: kube::Protocol::TCP,
+            port_name: None,
+            app_protocol: None,
+            subset: 0,
+            status: EndpointStatus::Healthy,
+            counter: kube::Counter { up: 0, down: 0 },
+            threshold: Default::default(),
+            probe_state: Default::default(),
+            admin: None,
+        };
+        let now = Instant::now();
+
+        // first one within an interval
+        assert!(!schedule.due(&mut ep, now));
+        let first = ep.probe_state.next.unwrap();
+        assert!(first <= now + schedule.interval);
+        assert!(!schedule.due(&mut ep, first - Duration::from_millis(1)));
+
+        assert!(schedule.due(&mut ep, first));
+        let next = ep.probe_state.next.unwrap() - first;
+        assert!(next >= Duration::from_millis(900) && next <= Duration::from_millis(1100));
+        // in flight
+        assert!(!schedule.due(&mut ep, first + Duration::from_secs(10)));
+
+        ep.probe_state.in_flight = false;
+        ep.status = EndpointStatus::Removed;
+        let removed = |backoff| {
+            let mut ep = ep.clone();
+            ep.probe_state.backoff = backoff;
+            schedule.interval_of(&ep)
+        };
+        assert_eq!(removed(0), Duration::from_secs(2));
+        assert_eq!(removed(1), Duration::from_secs(4));
+        assert_eq!(removed(2), Duration::from_secs(5));
+        assert_eq!(removed(40), Duration::from_secs(5));
+    }
+}
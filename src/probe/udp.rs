@@ -0,0 +1,332 @@
+use crate::error::{Error, Result};
+use regex::Regex;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+use super::BodyMatch;
+
+const MAX_DATAGRAM: usize = 65535;
+
+// sends a datagram, the endpoint is healthy if the response matches or, with
+//  no response expected, no ICMP port unreachable comes back within wait
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UdpProbe {
+    pub payload: Vec<u8>,
+    pub expect: Option<BodyMatch>,
+    // until the deadline of the probe if not given, an ICMP message from
+    //  another node may well take longer than a few milliseconds
+    pub wait: Option<Duration>,
+}
+
+impl UdpProbe {
+    // build from the key=value options of a probe spec
+    pub fn new(opts: &[(&str, Option<&str>)]) -> Result<Self> {
+        let mut probe = Self::default();
+        for (k, v) in opts {
+            match (*k, *v) {
+                ("payload", Some(v)) => probe.payload = v.as_bytes().to_vec(),
+                ("hex", Some(v)) => probe.payload = parse_hex(v)?,
+                ("expect", Some(v)) => probe.expect = Some(BodyMatch::Contains(v.to_owned())),
+                ("regex", Some(v)) => {
+                    let re = Regex::new(v)
+                        .map_err(|e| Error::config(format!("invalid regex {}: {}", v, e)))?;
+                    probe.expect = Some(BodyMatch::Regex(re));
+                }
+                ("wait", Some(v)) => {
+                    let ms = v
+                        .parse()
+                        .map_err(|_| Error::config(format!("invalid wait {}", v)))?;
+                    probe.wait = Some(Duration::from_millis(ms));
+                }
+                (k, _) => return Err(Error::config(format!("invalid udp probe option {}", k))),
+            }
+        }
+        Ok(probe)
+    }
+
+    pub async fn check(&self, addr: SocketAddr, deadline: Instant) -> Result<()> {
+        let wait = match (&self.expect, self.wait) {
+            (Some(_), _) => None,
+            (None, Some(wait)) => Some(deadline.min(Instant::now() + wait)),
+            (None, None) => Some(deadline),
+        };
+        let resp = match exchange(addr, &self.payload, wait).await? {
+            Some(resp) => resp,
+            None => return Ok(()),
+        };
+        if let Some(expect) = &self.expect {
+            if !expect.is_match(&String::from_utf8_lossy(&resp)) {
+                return Err(Error::probe("response doesn't match"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// DNS record types known by name
+const DNS_TYPES: &[(&str, u16)] = &[
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", 28),
+    ("SRV", 33),
+];
+
+// RCODEs of a DNS server which is working, whether or not the name exists
+const NOERROR: u16 = 0;
+const NXDOMAIN: u16 = 3;
+
+// queries a name, the endpoint is healthy if it answers with NOERROR or
+//  NXDOMAIN, or with anything at all if no name is given
+#[derive(Debug, Clone)]
+pub(crate) struct DnsProbe {
+    pub name: String,
+    pub qtype: u16,
+    // whether the rcode is checked
+    pub strict: bool,
+}
+
+impl Default for DnsProbe {
+    // The root NS. A forwarding resolver may well fail it, with SERVFAIL if
+    //  its upstream is unreachable or REFUSED if it doesn't recurse, which
+    //  says nothing of the resolver itself, so any response will do.
+    fn default() -> Self {
+        Self {
+            name: ".".to_owned(),
+            qtype: 2,
+            strict: false,
+        }
+    }
+}
+
+impl DnsProbe {
+    // build from the key=value options of a probe spec
+    pub fn new(opts: &[(&str, Option<&str>)]) -> Result<Self> {
+        let mut probe = Self::default();
+        for (k, v) in opts {
+            match (*k, *v) {
+                ("name", Some(v)) => {
+                    probe.name = v.to_owned();
+                    probe.strict = true;
+                }
+                ("type", Some(v)) => probe.qtype = parse_dns_type(v)?,
+                (k, _) => return Err(Error::config(format!("invalid dns probe option {}", k))),
+            }
+        }
+        // validate the name once rather than on every query
+        encode_query(0, &probe.name, probe.qtype)?;
+        Ok(probe)
+    }
+
+    pub async fn check(&self, addr: SocketAddr) -> Result<()> {
+        static ID: AtomicU16 = AtomicU16::new(0);
+        let id = ID.fetch_add(1, Ordering::Relaxed);
+        let query = encode_query(id, &self.name, self.qtype)?;
+        let resp = exchange(addr, &query, None)
+            .await?
+            .ok_or_else(|| Error::probe("no response"))?;
+        match decode_rcode(id, &resp)? {
+            _ if !self.strict => Ok(()),
+            NOERROR | NXDOMAIN => Ok(()),
+            rcode => Err(Error::probe(format!("dns rcode {}", rcode))),
+        }
+    }
+}
+
+// a port named dns or dns-*, or the port of DNS
+pub(crate) fn is_dns_port(name: Option<&str>, port: u16) -> bool {
+    let name = name.unwrap_or_default();
+    name == "dns" || name.starts_with("dns-") || port == 53
+}
+
+// Sends payload and waits for the response, forever unless a time to wait
+//  until is given in which case no response by then is Ok(None).
+// The socket is connected so that an ICMP port unreachable fails the receive.
+async fn exchange(
+    addr: SocketAddr,
+    payload: &[u8],
+    wait: Option<Instant>,
+) -> Result<Option<Vec<u8>>> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(payload).await?;
+
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let n = match wait {
+        Some(wait) => match time::timeout_at(wait, socket.recv(&mut buf)).await {
+            Ok(res) => res?,
+            Err(_) => return Ok(None),
+        },
+        None => socket.recv(&mut buf).await?,
+    };
+    buf.truncate(n);
+    Ok(Some(buf))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::config(format!("invalid hex {}", s));
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn parse_dns_type(s: &str) -> Result<u16> {
+    let upper = s.to_uppercase();
+    DNS_TYPES
+        .iter()
+        .find(|(name, _)| *name == upper)
+        .map(|(_, qtype)| *qtype)
+        .or_else(|| s.parse().ok())
+        .ok_or_else(|| Error::config(format!("invalid dns type {}", s)))
+}
+
+// a recursive query of one question in class IN
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(32);
+    query.extend_from_slice(&id.to_be_bytes());
+    // RD
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        if label.len() > 63 {
+            return Err(Error::config(format!("invalid dns name {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+fn decode_rcode(id: u16, resp: &[u8]) -> Result<u16> {
+    if resp.len() < 12 {
+        return Err(Error::probe("invalid dns response"));
+    }
+    if u16::from_be_bytes([resp[0], resp[1]]) != id {
+        return Err(Error::probe("unexpected dns response id"));
+    }
+    let flags = u16::from_be_bytes([resp[2], resp[3]]);
+    // QR
+    if flags & 0x8000 == 0 {
+        return Err(Error::probe("not a dns response"));
+    }
+    Ok(flags & 0x000f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_codec() {
+        let query = encode_query(0x1234, "kube-dns.kube-system.", 1).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x08kube-dns\x0bkube-system\x00\x00\x01\x00\x01");
+        assert_eq!(query, expected);
+        assert_eq!(encode_query(0, ".", 2).unwrap()[12..], [0, 0, 2, 0, 1]);
+
+        let mut resp = query.clone();
+        resp[2] |= 0x80;
+        resp[3] |= 0x03;
+        assert_eq!(decode_rcode(0x1234, &resp).unwrap(), NXDOMAIN);
+        assert!(decode_rcode(0x1234, &query).is_err());
+        assert!(decode_rcode(0x4321, &resp).is_err());
+
+        assert_eq!(parse_dns_type("aaaa").unwrap(), 28);
+        assert_eq!(parse_dns_type("65").unwrap(), 65);
+        assert!(parse_dns_type("BOGUS").is_err());
+    }
+
+    #[tokio::test]
+    async fn dns_check() {
+        // a resolver whose upstream is gone
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+                // QR, SERVFAIL
+                buf[2] |= 0x80;
+                buf[3] = (buf[3] & 0xf0) | 0x02;
+                server.send_to(&buf[..n], peer).await.unwrap();
+            }
+        });
+
+        DnsProbe::default().check(addr).await.unwrap();
+        let probe =
+            DnsProbe::new(&[("name", Some("kubernetes.default.svc.cluster.local"))]).unwrap();
+        assert!(probe.strict);
+        probe.check(addr).await.unwrap_err();
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("00ff7f").unwrap(), vec![0, 0xff, 0x7f]);
+        assert!(parse_hex("0").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            loop {
+                let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+                if &buf[..n] == b"ping" {
+                    server.send_to(b"pong", peer).await.unwrap();
+                }
+            }
+        });
+
+        let probe = UdpProbe::new(&[("payload", Some("ping")), ("expect", Some("pong"))]).unwrap();
+        let deadline = || Instant::now() + Duration::from_millis(200);
+        probe.check(addr, deadline()).await.unwrap();
+        // no response expected and none came by the deadline
+        let start = Instant::now();
+        UdpProbe::default().check(addr, deadline()).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let probe = UdpProbe::new(&[("wait", Some("20"))]).unwrap();
+        let start = Instant::now();
+        probe.check(addr, deadline()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        // nothing listens here, the ICMP port unreachable fails it
+        let closed = {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.local_addr().unwrap()
+        };
+        UdpProbe::default()
+            .check(closed, deadline())
+            .await
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,178 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorKind};
+use crate::kube::Services;
+use crate::{admin, metrics, status};
+
+// what a listener serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Api {
+    // metrics and the status of services, read only
+    Status,
+    // changes to endpoints, kept apart for it to be bound to a private address
+    Admin,
+}
+
+// Serves api over HTTP. It binds to addr right away so that startup fails if
+//  it can't.
+pub(crate) fn serve(
+    addr: SocketAddr,
+    api: Api,
+    svcs: Services,
+) -> hyper::Result<impl Future<Output = ()>> {
+    let make_svc = make_service_fn(move |_| {
+        let svcs = svcs.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let svcs = svcs.clone();
+                async move {
+                    let resp = match api {
+                        Api::Status => route(req, svcs).await,
+                        Api::Admin => route_admin(req, svcs).await,
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    info!("serving {:?} api on {}", api, addr);
+    Ok(async move {
+        if let Err(e) = server.await {
+            error!("server error: {}", e);
+        }
+    })
+}
+
+async fn route_admin(req: Request<Body>, svcs: Services) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/');
+    let segments: Vec<_> = path.split('/').collect();
+    if req.method() != Method::POST {
+        return not_found();
+    }
+    match segments[..] {
+        ["", "admin", namespace, name, endpoint, action] => {
+            admin(&svcs, namespace, name, endpoint, action, req.uri().query()).await
+        }
+        _ => not_found(),
+    }
+}
+
+async fn route(req: Request<Body>, svcs: Services) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/');
+    let segments: Vec<_> = path.split('/').collect();
+    if req.method() != Method::GET {
+        return not_found();
+    }
+    match segments[..] {
+        ["", "metrics"] => Response::builder()
+            .header(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(Body::from(metrics::gather(&svcs).await))
+            .unwrap(),
+        ["", "status"] => json(&status::services(&svcs).await),
+        ["", "status", namespace, name] => match status::service(&svcs, namespace, name).await {
+            Some(svc) => json(&svc),
+            None => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+// POST /admin/NAMESPACE/NAME/ENDPOINT/ACTION[?ttl=SECONDS]
+async fn admin(
+    svcs: &Services,
+    namespace: &str,
+    name: &str,
+    endpoint: &str,
+    action: &str,
+    query: Option<&str>,
+) -> Response<Body> {
+    let res = async {
+        let action = admin::Action::from_str(action)?;
+        let ttl = match url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .find(|(k, _)| k == "ttl")
+        {
+            Some((_, v)) => Some(Duration::from_secs(
+                v.parse()
+                    .map_err(|_| Error::config(format!("invalid ttl {}", v)))?,
+            )),
+            None => None,
+        };
+        admin::apply(svcs, namespace, name, endpoint, action, ttl).await
+    }
+    .await;
+    match res {
+        Ok(svc) => json(&svc),
+        Err(e) => {
+            error!(
+                "admin {} {} of {}/{}: {}",
+                action, endpoint, namespace, name, e
+            );
+            let status = match e.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                ErrorKind::Config => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Response::builder()
+                .status(status)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+fn json<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec_pretty(value) {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            error!("failed to encode status: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn bind_conflict() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(super::serve(addr, super::Api::Status, Default::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn admin_apart() {
+        let req = || {
+            super::Request::post("/admin/default/api/172.0.1.4/drain")
+                .body(super::Body::empty())
+                .unwrap()
+        };
+        let resp = super::route(req(), Default::default()).await;
+        assert_eq!(resp.status(), super::StatusCode::NOT_FOUND);
+        // no such service, but it got to the admin API
+        let resp = super::route_admin(req(), Default::default()).await;
+        assert_eq!(resp.status(), super::StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("no service default/api"));
+    }
+}
@@ -0,0 +1,182 @@
+use serde::Serialize;
+use std::time::{Instant, UNIX_EPOCH};
+
+use crate::kube::{self, EndpointStatus, Override, Services};
+
+// state of a service as served by the status API
+#[derive(Debug, Serialize)]
+pub(crate) struct Service {
+    pub name: String,
+    pub namespace: String,
+    pub our_version: String,
+    pub endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Endpoint {
+    pub addr: String,
+    pub protocol: &'static str,
+    pub port_name: Option<String>,
+    pub subset: usize,
+    pub status: &'static str,
+    pub up: u32,
+    pub down: u32,
+    pub restore_threshold: u32,
+    pub remove_threshold: u32,
+    pub last_probe: Option<Probe>,
+    // override set by an operator, drained or restored
+    pub admin: Option<&'static str>,
+    // seconds until the override expires
+    pub admin_ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Probe {
+    // seconds since the unix epoch
+    pub at: u64,
+    pub healthy: bool,
+    pub error: Option<String>,
+}
+
+impl From<&kube::Service> for Service {
+    fn from(svc: &kube::Service) -> Self {
+        Self {
+            name: svc.name.clone(),
+            namespace: svc.namespace.clone(),
+            our_version: svc.our_version.clone(),
+            endpoints: svc.endpoints.iter().map(Endpoint::from).collect(),
+        }
+    }
+}
+
+impl From<&kube::Endpoint> for Endpoint {
+    fn from(ep: &kube::Endpoint) -> Self {
+        Self {
+            addr: ep.addr.to_string(),
+            protocol: match ep.protocol {
+                kube::Protocol::TCP => "TCP",
+                kube::Protocol::UDP => "UDP",
+            },
+            port_name: ep.port_name.clone(),
+            subset: ep.subset,
+            status: match ep.status {
+                EndpointStatus::Healthy => "healthy",
+                EndpointStatus::Removed => "removed",
+            },
+            up: ep.counter.up,
+            down: ep.counter.down,
+            restore_threshold: ep.threshold.restore,
+            remove_threshold: ep.threshold.remove,
+            last_probe: ep.probe_state.last.as_ref().map(|last| Probe {
+                at: last
+                    .at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                healthy: last.error.is_none(),
+                error: last.error.clone(),
+            }),
+            admin: ep.admin.as_ref().map(|admin| match admin.state {
+                Override::Drained => "drained",
+                Override::Restored => "restored",
+            }),
+            admin_ttl: ep
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.until)
+                .map(|until| until.saturating_duration_since(Instant::now()).as_secs()),
+        }
+    }
+}
+
+// every service being checked, sorted by namespace/name
+pub(crate) async fn services(svcs: &Services) -> Vec<Service> {
+    let mut svcs: Vec<_> = svcs
+        .read()
+        .await
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    svcs.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut res = Vec::with_capacity(svcs.len());
+    for (_, svc) in svcs {
+        res.push(Service::from(&*svc.read().await));
+    }
+    res
+}
+
+// a single service, None if it isn't being checked
+pub(crate) async fn service(svcs: &Services, namespace: &str, name: &str) -> Option<Service> {
+    let svc = svcs
+        .read()
+        .await
+        .get(&kube::key(namespace, name))
+        .cloned()?;
+    let svc = svc.read().await;
+    Some(Service::from(&*svc))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kube;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn services() {
+        let yml_str = "
+        apiVersion: v1
+        kind: Endpoints
+        metadata:
+          name: api
+          namespace: default
+          resourceVersion: \"1\"
+        subsets:
+        - addresses:
+          - ip: 172.0.1.4
+          - ip: 172.0.1.5
+          ports:
+          - name: http
+            port: 80
+            protocol: TCP";
+        let mut svc = kube::Service::new(
+            yml_str.to_owned(),
+            kube::Threshold {
+                restore: 3,
+                remove: 2,
+            },
+            Arc::new(crate::alert::Alert::default()),
+            kube::tests::client(),
+        )
+        .unwrap()
+        .unwrap();
+        svc.endpoints[0].counter.down = 1;
+        svc.endpoints[0].probe_state.last = Some(kube::ProbeResult {
+            at: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            error: Some("connection refused".to_owned()),
+        });
+        let svcs: kube::Services = Arc::new(RwLock::new(HashMap::new()));
+        svcs.write()
+            .await
+            .insert(svc.key(), Arc::new(RwLock::new(svc)));
+
+        let all = super::services(&svcs).await;
+        assert_eq!(all.len(), 1);
+        let json = serde_json::to_value(&all[0]).unwrap();
+        assert_eq!(json["our_version"], "1");
+        let ep = &json["endpoints"][0];
+        assert_eq!(ep["addr"], "172.0.1.4:80");
+        assert_eq!(ep["status"], "healthy");
+        assert_eq!(ep["down"], 1);
+        assert_eq!(ep["remove_threshold"], 2);
+        assert_eq!(ep["last_probe"]["at"], 1_600_000_000);
+        assert_eq!(ep["last_probe"]["healthy"], false);
+        assert_eq!(ep["last_probe"]["error"], "connection refused");
+        assert!(json["endpoints"][1]["last_probe"].is_null());
+
+        assert!(super::service(&svcs, "default", "api").await.is_some());
+        assert!(super::service(&svcs, "default", "web").await.is_none());
+    }
+}